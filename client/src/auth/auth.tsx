@@ -1,7 +1,7 @@
-import { Component, createSignal, onMount } from "solid-js";
+import { Component, Show, createSignal, onMount } from "solid-js";
 import * as squarewordState from "../squareword/state";
 import * as sudokuState from "../sudoku/state";
-import { baseUrl } from "../util";
+import { baseUrl, errorMessage } from "../util";
 import jwt_decode from "jwt-decode";
 
 export const [token, setToken] = createSignal<string | null>(null);
@@ -32,7 +32,155 @@ export function profilePic() {
     return payload['picture'];
 }
 
+export function isGuest() {
+    const t = token();
+
+    if (!t) {
+        return false;
+    }
+
+    const payload = jwt_decode(t) as any;
+    return payload['guest'] === true;
+}
+
+type Session = {
+    access_token: string,
+    expires_at: string,
+    refresh_token: string,
+};
+
+function setSession(session: Session) {
+    setToken(session.access_token);
+    localStorage.setItem('refreshToken', session.refresh_token);
+}
+
+let refreshing: Promise<boolean> | null = null;
+
+// Each refresh token only works once, so concurrent callers share a single refresh
+function refreshSession(): Promise<boolean> {
+    if (refreshing === null) {
+        refreshing = (async () => {
+            const refreshToken = localStorage.getItem('refreshToken');
+            if (refreshToken === null) {
+                return false;
+            }
+
+            const res = await fetch(`${baseUrl()}/refresh`, {
+                method: 'POST',
+                headers: {
+                    'Content-Type': 'application/json'
+                },
+                body: JSON.stringify({ refresh_token: refreshToken })
+            });
+            if (res.status !== 200) {
+                return false;
+            }
+
+            setSession(await res.json());
+            return true;
+        })().finally(() => {
+            refreshing = null;
+        });
+    }
+
+    return refreshing;
+}
+
+// Fetches with the access token, refreshing the session and retrying once if it has expired
+export async function authFetch(input: string, init: RequestInit = {}): Promise<Response> {
+    const withToken = () => ({
+        ...init,
+        headers: {
+            ...(init.headers as Record<string, string> | undefined),
+            'Authorization': `Bearer ${token()}`,
+        },
+    });
+
+    const res = await fetch(input, withToken());
+    if (res.status !== 401) {
+        return res;
+    }
+
+    if (!await refreshSession()) {
+        logout();
+        return res;
+    }
+
+    return fetch(input, withToken());
+}
+
+export async function playAsGuest() {
+    const res = await fetch(`${baseUrl()}/guest`, { method: 'POST' });
+    if (res.status !== 200) {
+        alert(`Couldn't start a guest session: ${errorMessage(await res.text())}`);
+        return;
+    }
+
+    setSession(await res.json());
+
+    squarewordState.loadGameFromServer();
+    sudokuState.loadGameFromServer();
+}
+
+// Keeps the guest's refresh token across the login screen, so the server can merge their scores
+// into the account they sign in to
+export function signInFromGuest() {
+    const refreshToken = localStorage.getItem('refreshToken');
+    sudokuState.clearAll();
+    squarewordState.clearAll();
+    setToken(null);
+    localStorage.clear();
+    if (refreshToken !== null) {
+        localStorage.setItem('guestRefreshToken', refreshToken);
+    }
+    window.location.href = '/';
+}
+
 export function logout() {
+    const refreshToken = localStorage.getItem('refreshToken');
+    if (refreshToken !== null) {
+        fetch(`${baseUrl()}/logout`, {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json'
+            },
+            body: JSON.stringify({ refresh_token: refreshToken }),
+            keepalive: true,
+        });
+    }
+
+    sudokuState.clearAll();
+    squarewordState.clearAll();
+    setToken(null);
+    localStorage.clear();
+    window.location.href = '/';
+}
+
+export async function exportAccount() {
+    const res = await authFetch(`${baseUrl()}/me/export`);
+    if (res.status !== 200) {
+        alert(`Export failed: ${errorMessage(await res.text())}`);
+        return;
+    }
+
+    const link = document.createElement('a');
+    link.href = URL.createObjectURL(await res.blob());
+    link.download = 'gotd-export.json';
+    link.click();
+    URL.revokeObjectURL(link.href);
+}
+
+export async function deleteAccount() {
+    if (!confirm('Delete your account and every score you have? This can\'t be undone.')) {
+        return;
+    }
+
+    const res = await authFetch(`${baseUrl()}/me`, { method: 'DELETE' });
+    if (res.status !== 204) {
+        alert(`Deleting your account failed: ${errorMessage(await res.text())}`);
+        return;
+    }
+
     sudokuState.clearAll();
     squarewordState.clearAll();
     setToken(null);
@@ -49,17 +197,21 @@ const LoginButton: Component = () => {
             headers: {
                 'Content-Type': 'application/json'
             },
-            body: JSON.stringify({ token: response.credential })
+            body: JSON.stringify({
+                token: response.credential,
+                guest_refresh_token: localStorage.getItem('guestRefreshToken'),
+            })
         });
 
         const text = await res.text();
 
         if (res.status !== 200) {
-            alert(`Login failed: ${text}`);
+            alert(`Login failed: ${errorMessage(text)}`);
             return;
         }
 
-        setToken(text);
+        localStorage.removeItem('guestRefreshToken');
+        setSession(JSON.parse(text));
 
         squarewordState.loadGameFromServer();
         sudokuState.loadGameFromServer();
@@ -67,7 +219,11 @@ const LoginButton: Component = () => {
 
     onMount(() => {
         window.onload = () => {
+            const guestRefreshToken = localStorage.getItem('guestRefreshToken');
             localStorage.clear();
+            if (guestRefreshToken !== null) {
+                localStorage.setItem('guestRefreshToken', guestRefreshToken);
+            }
 
             google.accounts.id.initialize({
                 client_id: "1012807370880-93eor5h650abjrreks9ut5f5dp5tv67q.apps.googleusercontent.com",
@@ -93,9 +249,19 @@ export const LoginScreen: Component = () => {
             <span class='text-xl text-center mx-4 mb-3'>
                 Sign in using the button below, or use the google popup if you see it.
             </span>
+            <Show when={localStorage.getItem('guestRefreshToken') !== null}>
+                <span class='text-center mx-4 mb-3'>
+                    Your guest scores will be added to the account you sign in to.
+                </span>
+            </Show>
             <div>
                 <LoginButton />
             </div>
+            <Show when={localStorage.getItem('guestRefreshToken') === null}>
+                <button class='mt-4 text-blue-500 underline' onClick={() => playAsGuest()}>
+                    Play as a guest
+                </button>
+            </Show>
         </div>
     );
 };
@@ -1,5 +1,5 @@
 import { Component, Show, createSignal } from "solid-js";
-import { logout, profilePic, username } from "../auth/auth";
+import { deleteAccount, exportAccount, isGuest, logout, profilePic, signInFromGuest, username } from "../auth/auth";
 import { FiHome } from 'solid-icons/fi'
 
 export const TopBar: Component<{ title: string }> = (props) => {
@@ -55,9 +55,22 @@ export const TopBar: Component<{ title: string }> = (props) => {
                     >
                         <div class='p-3'>{username()}</div>
                         <div class='w-[100%] h-0 border-b border-stone-400' />
-                        <button class='w-full p-3 hover:bg-stone-200 text-start' onClick={signOut}>
-                            Sign out
+                        <button class='w-full p-3 hover:bg-stone-200 text-start' onClick={() => exportAccount()}>
+                            Export my data
                         </button>
+                        <button class='w-full p-3 hover:bg-stone-200 text-start text-red-600' onClick={() => deleteAccount()}>
+                            Delete account
+                        </button>
+                        <div class='w-[100%] h-0 border-b border-stone-400' />
+                        <Show when={isGuest()} fallback={
+                            <button class='w-full p-3 hover:bg-stone-200 text-start' onClick={signOut}>
+                                Sign out
+                            </button>
+                        }>
+                            <button class='w-full p-3 hover:bg-stone-200 text-start' onClick={() => signInFromGuest()}>
+                                Sign in to keep your scores
+                            </button>
+                        </Show>
                     </div>
                 </div>
             </div>
@@ -1,23 +1,28 @@
-import { Component, For, createSignal, onMount } from "solid-js";
+import { Component, For, createSignal, onCleanup, onMount } from "solid-js";
 import { baseUrl } from "../util";
 
 type Leader = {
+    rank: number,
+    user_id: string,
     name: string,
     picture: string | null,
-    sudoku_score: number,
-    squareword_score: number,
+    score: number,
+    solves: number,
+    finished_at: string,
 }
 
 export const Leaderboard: Component = () => {
     const [leaders, setLeaders] = createSignal<Leader[]>([]);
 
     onMount(() => {
-        fetch(`${baseUrl()}/leaderboard`)
-            .then(res => res.json())
-            .then(data => {
-                console.log(data.users);
-                setLeaders(data.users);
-            });
+        // The stream sends the current board first, then again whenever it changes
+        const events = new EventSource(`${baseUrl()}/leaderboard/stream?game=sudoku`);
+        events.addEventListener('leaderboard', e => {
+            const data = JSON.parse((e as MessageEvent).data);
+            setLeaders(data.users);
+        });
+
+        onCleanup(() => events.close());
     });
 
     return (
@@ -1,6 +1,6 @@
-import { createSignal } from 'solid-js';
+import { createSignal, untrack } from 'solid-js';
 import { baseUrl, daysEqual, getDay } from '../util';
-import { token } from '../auth/auth';
+import { authFetch } from '../auth/auth';
 
 export const [id, setId] = createSignal<string | null>(null);
 export const [loading, setLoading] = createSignal(false);
@@ -9,6 +9,7 @@ export const [guess, setGuess] = createSignal<Array<string>>([]);
 export const [guessHistory, setGuessHistory] = createSignal<Array<string>>([]);
 export const [puzzleDay, setPuzzleDay] = createSignal<Date | null>(null);
 export const [winner, setWinner] = createSignal(false);
+export const [version, setVersion] = createSignal(0);
 
 export function clearAll() {
     setId(null);
@@ -18,6 +19,7 @@ export function clearAll() {
     setGuessHistory([]);
     setPuzzleDay(null);
     setWinner(false);
+    setVersion(0);
 }
 
 export async function loadGameFromServer() {
@@ -29,7 +31,7 @@ export async function loadGameFromServer() {
     let local = localStorage.getItem('squareword');
     let localTimestamp: number | null = null;
     if (local !== null) {
-        let { id, puzzleDay, solution, guess, guessHistory, winner, timestamp } = JSON.parse(local);
+        let { id, puzzleDay, solution, guess, guessHistory, winner, timestamp, version } = JSON.parse(local);
 
         puzzleDay = new Date(puzzleDay);
 
@@ -42,6 +44,7 @@ export async function loadGameFromServer() {
             setGuess(guess);
             setGuessHistory(guessHistory);
             setWinner(winner);
+            setVersion(version ?? 0);
 
             if (timestamp >= Date.now() - 1000 * 10) {
                 setLoading(false);
@@ -50,11 +53,7 @@ export async function loadGameFromServer() {
         }
     }
 
-    const res = await fetch(`${baseUrl()}/squareword/state`, {
-        headers: {
-            'Authorization': `Bearer ${token()}`
-        }
-    });
+    const res = await authFetch(`${baseUrl()}/squareword/state`);
     const resJson = await res.json();
 
     if (resJson.timestamp && localTimestamp) {
@@ -75,6 +74,7 @@ export async function loadGameFromServer() {
     ]);
 
     setId(resJson.id);
+    setVersion(resJson.version ?? 0);
 
     let [y, m, d] = resJson.day.split('-');
     let date = new Date(parseInt(y), parseInt(m) - 1, parseInt(d));
@@ -91,6 +91,7 @@ export async function loadGameFromServer() {
             winner: resJson.winner,
             puzzleDay: date,
             timestamp: resJson.timestamp,
+            version: resJson.version,
         }));
     } else {
         localStorage.setItem('squareword', JSON.stringify({
@@ -101,12 +102,18 @@ export async function loadGameFromServer() {
             guessHistory: guessHistory(),
             winner: resJson.winner,
             timestamp: resJson.timestamp,
+            version: resJson.version ?? 0,
         }));
     }
 
     setLoading(false);
 }
 
+// Only one save is sent at a time. Changes made while it's in flight are sent together once it
+// comes back, with the version it returned.
+let saving = false;
+let saveQueued = false;
+
 export async function saveState() {
     if (!daysEqual(puzzleDay(), getDay())) {
         await loadGameFromServer();
@@ -120,14 +127,38 @@ export async function saveState() {
         return;
     }
 
-    const headers = {
-        'Content-Type': 'application/json',
-        'Authorization': `Bearer ${token()}`,
-    };
+    localStorage.setItem('squareword', JSON.stringify({
+        id: id(),
+        puzzleDay: puzzleDay(),
+        solution: solution(),
+        guess: guess(),
+        guessHistory: guessHistory(),
+        winner: winner(),
+        timestamp: Date.now(),
+        // Untracked, so the version a save returns doesn't set off another save
+        version: untrack(version),
+    }));
 
-    const timestamp = Date.now();
+    if (saving) {
+        saveQueued = true;
+        return;
+    }
+    saving = true;
+    try {
+        let saved: boolean;
+        do {
+            saveQueued = false;
+            saved = await sendState();
+            // A save that failed isn't retried until the next change
+        } while (saved && saveQueued);
+    } finally {
+        saving = false;
+    }
+}
 
-    const body = JSON.stringify({
+// Sends the current state, returning whether it was saved
+async function sendState(): Promise<boolean> {
+    const body = untrack(() => JSON.stringify({
         puzzle_id: id(),
         state: JSON.stringify({
             'id': id(),
@@ -136,24 +167,30 @@ export async function saveState() {
             'puzzleDay': puzzleDay(),
         }),
         winner: winner(),
-        timestamp: timestamp,
-    });
-
-    localStorage.setItem('squareword', JSON.stringify({
-        id: id(),
-        puzzleDay: puzzleDay(),
-        solution: solution(),
-        guess: guess(),
-        guessHistory: guessHistory(),
-        winner: winner(),
-        timestamp: timestamp,
+        timestamp: Date.now(),
+        version: version(),
     }));
 
-    await fetch(`${baseUrl()}/squareword/state`, {
+    const res = await authFetch(`${baseUrl()}/squareword/state`, {
         method: 'POST',
-        headers: headers,
+        headers: {
+            'Content-Type': 'application/json',
+        },
         body: body,
     });
+
+    // The server has a newer save from somewhere else, so drop ours and take that one
+    if (res.status === 409) {
+        localStorage.removeItem('squareword');
+        await loadGameFromServer();
+        return false;
+    }
+
+    if (res.ok) {
+        setVersion((await res.json()).version);
+        return true;
+    }
+    return false;
 }
 
 export function formatScore(): string {
@@ -1,6 +1,6 @@
-import { createSignal } from "solid-js";
+import { createSignal, untrack } from "solid-js";
 import { baseUrl, daysEqual, formatTime, getDay } from "../util";
-import { token } from "../auth/auth";
+import { authFetch } from "../auth/auth";
 
 export const [id, setId] = createSignal<string | null>(null);
 export const [paused, setPaused] = createSignal(false);
@@ -10,6 +10,7 @@ export const [puzzleDay, setPuzzleDay] = createSignal<Date | null>(null);
 export const [solution, setSolution] = createSignal<string | null>(null);
 export const [loading, setLoading] = createSignal(false);
 export const [winner, setWinner] = createSignal(false);
+export const [version, setVersion] = createSignal(0);
 
 export function clearAll() {
     setId(null);
@@ -20,6 +21,7 @@ export function clearAll() {
     setSolution(null);
     setLoading(false);
     setWinner(false);
+    setVersion(0);
     setHistory(null);
 }
 
@@ -39,6 +41,11 @@ export type History = GameState[];
 
 export const [history, setHistory] = createSignal<History | null>(null, { equals: false });
 
+// Only one save is sent at a time. Changes made while it's in flight are sent together once it
+// comes back, with the version it returned.
+let saving = false;
+let saveQueued = false;
+
 export async function saveState() {
     if (!daysEqual(puzzleDay(), getDay())) {
         await loadGameFromServer();
@@ -52,18 +59,6 @@ export async function saveState() {
         return;
     }
 
-    let historyLast1 = history()?.slice(-1) ?? null;
-    if (historyLast1?.length == 0) {
-        historyLast1 = null;
-    }
-
-    const headers = {
-        'Content-Type': 'application/json',
-        'Authorization': `Bearer ${token()}`,
-    };
-
-    const timestamp = Date.now();
-
     localStorage.setItem('sudoku', JSON.stringify({
         id: id(),
         solution: solution(),
@@ -73,28 +68,72 @@ export async function saveState() {
         winner: winner(),
         inputStyle: inputStyle(),
         puzzleDay: puzzleDay(),
-        timestamp: timestamp,
+        timestamp: Date.now(),
+        // Untracked, so the version a save returns doesn't set off another save
+        version: untrack(version),
     }));
 
-    const body = JSON.stringify({
-        puzzle_id: id(),
-        state: JSON.stringify({
-            id: id(),
-            paused: paused(),
-            seconds: seconds(),
-            inputStyle: inputStyle(),
-            history: historyLast1,
-            puzzleDay: puzzleDay(),
-        }),
-        timestamp: timestamp,
-        winner: winner(),
+    if (saving) {
+        saveQueued = true;
+        return;
+    }
+    saving = true;
+    try {
+        let saved: boolean;
+        do {
+            saveQueued = false;
+            saved = await sendState();
+            // A save that failed isn't retried until the next change
+        } while (saved && saveQueued);
+    } finally {
+        saving = false;
+    }
+}
+
+// Sends the current state, returning whether it was saved
+async function sendState(): Promise<boolean> {
+    const body = untrack(() => {
+        let historyLast1 = history()?.slice(-1) ?? null;
+        if (historyLast1?.length == 0) {
+            historyLast1 = null;
+        }
+
+        return JSON.stringify({
+            puzzle_id: id(),
+            state: JSON.stringify({
+                id: id(),
+                paused: paused(),
+                seconds: seconds(),
+                inputStyle: inputStyle(),
+                history: historyLast1,
+                puzzleDay: puzzleDay(),
+            }),
+            timestamp: Date.now(),
+            winner: winner(),
+            version: version(),
+        });
     });
 
-    await fetch(`${baseUrl()}/sudoku/state`, {
+    const res = await authFetch(`${baseUrl()}/sudoku/state`, {
         method: 'POST',
-        headers: headers,
+        headers: {
+            'Content-Type': 'application/json',
+        },
         body: body
     });
+
+    // The server has a newer save from somewhere else, so drop ours and take that one
+    if (res.status === 409) {
+        localStorage.removeItem('sudoku');
+        await loadGameFromServer();
+        return false;
+    }
+
+    if (res.ok) {
+        setVersion((await res.json()).version);
+        return true;
+    }
+    return false;
 }
 
 export async function loadGameFromServer() {
@@ -106,7 +145,7 @@ export async function loadGameFromServer() {
     let local = localStorage.getItem('sudoku');
     let localTimestamp = null;
     if (local !== null) {
-        let { id, solution, seconds, paused, history, inputStyle, puzzleDay, winner, timestamp } = JSON.parse(local);
+        let { id, solution, seconds, paused, history, inputStyle, puzzleDay, winner, timestamp, version } = JSON.parse(local);
 
         puzzleDay = new Date(puzzleDay);
 
@@ -121,6 +160,7 @@ export async function loadGameFromServer() {
             setPuzzleDay(puzzleDay);
             setWinner(winner);
             setSolution(solution);
+            setVersion(version ?? 0);
 
             if (timestamp >= Date.now() - 1000 * 60) {
                 setLoading(false);
@@ -129,11 +169,7 @@ export async function loadGameFromServer() {
         }
     }
 
-    const res = await fetch(`${baseUrl()}/sudoku/state`, {
-        headers: {
-            'Authorization': `Bearer ${token()}`
-        }
-    });
+    const res = await authFetch(`${baseUrl()}/sudoku/state`);
     const resJson = await res.json();
 
     if (resJson.timestamp) {
@@ -150,6 +186,7 @@ export async function loadGameFromServer() {
 
     setSolution(resJson.solution);
     setId(resJson.id);
+    setVersion(resJson.version ?? 0);
 
     if (resJson.state !== null) {
         localStorage.setItem('sudoku', JSON.stringify({
@@ -158,6 +195,7 @@ export async function loadGameFromServer() {
             winner: resJson.winner,
             puzzleDay: date,
             timestamp: resJson.timestamp,
+            version: resJson.version,
         }));
 
         let { seconds, paused, inputStyle, history } = JSON.parse(resJson.state);
@@ -203,6 +241,7 @@ export async function loadGameFromServer() {
             winner: false,
             puzzleDay: date,
             timestamp: Date.now(),
+            version: 0,
         }));
     }
 
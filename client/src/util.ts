@@ -1,7 +1,8 @@
 export function baseUrl(): string {
-    // return 'https://mpro.crussell.io:3001';
-    // return 'http://localhost:3001';
-    return 'https://gotd.crussell.io/api';
+    // The proxy strips its own /api prefix, so this is the server's /api/v1
+    // return 'https://mpro.crussell.io:3001/api/v1';
+    // return 'http://localhost:3001/api/v1';
+    return 'https://gotd.crussell.io/api/api/v1';
 }
 
 export function dateAtMidnight(d: Date): Date {
@@ -27,3 +28,12 @@ export function formatTime(seconds: number): string {
     const secondsLeft = seconds % 60;
     return `${minutes}:${secondsLeft.toString().padStart(2, '0')} `;
 }
+
+// The message from one of the server's JSON errors, or the raw body if it isn't one
+export function errorMessage(body: string): string {
+    try {
+        return JSON.parse(body).message ?? body;
+    } catch {
+        return body;
+    }
+}
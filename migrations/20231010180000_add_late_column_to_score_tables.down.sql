@@ -0,0 +1,5 @@
+-- Remove 'late' column from 'sudoku_scores'
+alter table "sudoku_scores" drop column "late";
+
+-- Remove 'late' column from 'squareword_scores'
+alter table "squareword_scores" drop column "late";
@@ -0,0 +1,5 @@
+-- Add 'late' column to 'sudoku_scores'
+alter table "sudoku_scores" add column "late" boolean not null default false;
+
+-- Add 'late' column to 'squareword_scores'
+alter table "squareword_scores" add column "late" boolean not null default false;
@@ -0,0 +1,13 @@
+-- Remove structured score columns from 'sudoku_scores'
+alter table "sudoku_scores" drop column "elapsed_seconds";
+alter table "sudoku_scores" drop column "guess_count";
+alter table "sudoku_scores" drop column "mistakes";
+alter table "sudoku_scores" drop column "hints_used";
+alter table "sudoku_scores" drop column "completed_at";
+
+-- Remove structured score columns from 'squareword_scores'
+alter table "squareword_scores" drop column "elapsed_seconds";
+alter table "squareword_scores" drop column "guess_count";
+alter table "squareword_scores" drop column "mistakes";
+alter table "squareword_scores" drop column "hints_used";
+alter table "squareword_scores" drop column "completed_at";
@@ -0,0 +1,56 @@
+-- Add structured score columns to 'sudoku_scores'
+alter table "sudoku_scores" add column "elapsed_seconds" integer;
+alter table "sudoku_scores" add column "guess_count" integer;
+alter table "sudoku_scores" add column "mistakes" integer;
+alter table "sudoku_scores" add column "hints_used" integer;
+alter table "sudoku_scores" add column "completed_at" timestamp with time zone;
+
+-- Add structured score columns to 'squareword_scores'
+alter table "squareword_scores" add column "elapsed_seconds" integer;
+alter table "squareword_scores" add column "guess_count" integer;
+alter table "squareword_scores" add column "mistakes" integer;
+alter table "squareword_scores" add column "hints_used" integer;
+alter table "squareword_scores" add column "completed_at" timestamp with time zone;
+
+-- The state column has always accepted any text, so rows that don't parse are left unfilled
+-- rather than failing the migration
+create function pg_temp.try_json(value text) returns json as $$
+begin
+    return value::json;
+exception when others then
+    return null;
+end;
+$$ language plpgsql immutable;
+
+-- Backfill 'sudoku_scores' from the serialized client state
+update "sudoku_scores" s set
+    "elapsed_seconds" = round((parsed.state->>'seconds')::numeric)::integer,
+    "mistakes" = case when json_typeof(parsed.state->'history'->-1->'cells') = 'array' then (
+        select count(*) from json_array_elements(parsed.state->'history'->-1->'cells') c
+        where c->>'check' = 'false'
+    ) end,
+    "hints_used" = 0
+from (
+    select "id", pg_temp.try_json("state") as state from "sudoku_scores" where "state" is not null
+) parsed
+where s."id" = parsed."id"
+    -- A case, since the planner is free to run the cast before checking the type
+    and case when json_typeof(parsed.state->'seconds') = 'number'
+        then (parsed.state->>'seconds')::numeric between 0 and 2147483647 end
+    and json_typeof(parsed.state->'history') = 'array';
+
+-- Backfill 'squareword_scores' from the serialized client state
+update "squareword_scores" s set
+    "guess_count" = json_array_length(parsed.state->'guessHistory'),
+    "hints_used" = 0
+from (
+    select "id", pg_temp.try_json("state") as state from "squareword_scores" where "state" is not null
+) parsed
+where s."id" = parsed."id" and json_typeof(parsed.state->'guessHistory') = 'array';
+
+-- The client timestamp of the winning save is the best record of when a puzzle was finished
+update "sudoku_scores" set "completed_at" = to_timestamp("timestamp" / 1000.0)
+where "winner" and "timestamp" > 0;
+
+update "squareword_scores" set "completed_at" = to_timestamp("timestamp" / 1000.0)
+where "winner" and "timestamp" > 0;
@@ -0,0 +1,5 @@
+-- Remove 'version' column from 'sudoku_scores'
+alter table "sudoku_scores" drop column "version";
+
+-- Remove 'version' column from 'squareword_scores'
+alter table "squareword_scores" drop column "version";
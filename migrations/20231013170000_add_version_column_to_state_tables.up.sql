@@ -0,0 +1,5 @@
+-- Add 'version' column to 'sudoku_scores'
+alter table "sudoku_scores" add column "version" bigint not null default 1;
+
+-- Add 'version' column to 'squareword_scores'
+alter table "squareword_scores" add column "version" bigint not null default 1;
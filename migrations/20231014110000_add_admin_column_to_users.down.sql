@@ -0,0 +1,2 @@
+-- Remove 'admin' column from 'users'
+alter table "users" drop column "admin";
@@ -0,0 +1,2 @@
+-- Add 'admin' column to 'users'. Admins can issue password resets for other accounts.
+alter table "users" add column "admin" boolean not null default false;
@@ -0,0 +1,15 @@
+create table if not exists "moves" (
+    "id" uuid primary key,
+    "user_id" uuid not null,
+    "game" text not null,
+    "puzzle_id" uuid not null,
+    "seq" integer not null,
+    "move" text not null,
+    "client_timestamp" bigint not null,
+    "created_at" timestamp with time zone not null,
+
+    foreign key ("user_id") references "users" ("id"),
+
+    constraint "moves_game_check" check ("game" in ('sudoku', 'squareword')),
+    constraint "moves_user_id_game_puzzle_id_seq_key" unique ("user_id", "game", "puzzle_id", "seq")
+);
@@ -0,0 +1,2 @@
+drop table "race_participants" cascade;
+drop table "races" cascade;
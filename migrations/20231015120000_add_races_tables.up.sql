@@ -0,0 +1,31 @@
+create table if not exists "races" (
+    "id" uuid primary key,
+    "code" text not null,
+    "game" text not null,
+    "puzzle" text,
+    "solution" text,
+    "created_by" uuid not null,
+    "created_at" timestamp with time zone not null,
+    "started_at" timestamp with time zone,
+    "finished_at" timestamp with time zone,
+    "winner_id" uuid,
+
+    foreign key ("created_by") references "users" ("id"),
+    foreign key ("winner_id") references "users" ("id"),
+
+    constraint "races_game_check" check ("game" in ('sudoku', 'squareword')),
+    constraint "races_code_key" unique ("code")
+);
+
+create table if not exists "race_participants" (
+    "race_id" uuid not null,
+    "user_id" uuid not null,
+    "joined_at" timestamp with time zone not null,
+    "progress" integer not null default 0,
+    "finished_at" timestamp with time zone,
+    "place" integer,
+
+    primary key ("race_id", "user_id"),
+    foreign key ("race_id") references "races" ("id"),
+    foreign key ("user_id") references "users" ("id")
+);
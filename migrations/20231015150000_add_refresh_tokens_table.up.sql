@@ -0,0 +1,12 @@
+create table if not exists "refresh_tokens" (
+    "id" uuid primary key,
+    "user_id" uuid not null,
+    "token_hash" text not null,
+    "created_at" timestamp with time zone not null,
+    "expires_at" timestamp with time zone not null,
+    "revoked_at" timestamp with time zone,
+
+    foreign key ("user_id") references "users" ("id"),
+
+    constraint "refresh_tokens_token_hash_key" unique ("token_hash")
+);
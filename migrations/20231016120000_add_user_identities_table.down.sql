@@ -0,0 +1,4 @@
+drop table "user_identities" cascade;
+
+-- Remove 'provider' column from 'users'
+alter table "users" drop column "provider";
@@ -0,0 +1,15 @@
+-- Add 'provider' column to 'users'. Everyone so far signed in with Google.
+alter table "users" add column "provider" text not null default 'google';
+
+create table if not exists "user_identities" (
+    "id" uuid primary key,
+    "user_id" uuid not null,
+    "provider" text not null,
+    "subject" text not null,
+    "email" text not null,
+    "created_at" timestamp with time zone not null,
+
+    foreign key ("user_id") references "users" ("id"),
+
+    constraint "user_identities_provider_subject_key" unique ("provider", "subject")
+);
@@ -0,0 +1,17 @@
+-- Guests can't be kept without an email address
+delete from "moves" where "user_id" in (select "id" from "users" where "guest");
+delete from "sudoku_scores" where "user_id" in (select "id" from "users" where "guest");
+delete from "squareword_scores" where "user_id" in (select "id" from "users" where "guest");
+delete from "race_participants" where "user_id" in (select "id" from "users" where "guest");
+update "races" set "winner_id" = null where "winner_id" in (select "id" from "users" where "guest");
+delete from "race_participants" where "race_id" in (
+    select "id" from "races" where "created_by" in (select "id" from "users" where "guest")
+);
+delete from "races" where "created_by" in (select "id" from "users" where "guest");
+delete from "refresh_tokens" where "user_id" in (select "id" from "users" where "guest");
+delete from "users" where "guest";
+
+alter table "users" alter column "email" set not null;
+
+-- Remove 'guest' column from 'users'
+alter table "users" drop column "guest";
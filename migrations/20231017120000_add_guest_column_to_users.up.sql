@@ -0,0 +1,5 @@
+-- Add 'guest' column to 'users'
+alter table "users" add column "guest" boolean not null default false;
+
+-- Guests sign in without an identity provider, so they have no email address
+alter table "users" alter column "email" drop not null;
@@ -0,0 +1,2 @@
+drop table "password_resets" cascade;
+drop table "password_credentials" cascade;
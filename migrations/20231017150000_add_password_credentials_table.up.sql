@@ -0,0 +1,23 @@
+create table if not exists "password_credentials" (
+    "user_id" uuid primary key,
+    "password_hash" text not null,
+    "created_at" timestamp with time zone not null,
+    "updated_at" timestamp with time zone not null,
+
+    foreign key ("user_id") references "users" ("id")
+);
+
+create table if not exists "password_resets" (
+    "id" uuid primary key,
+    "user_id" uuid not null,
+    "token_hash" text not null,
+    "created_by" uuid not null,
+    "created_at" timestamp with time zone not null,
+    "expires_at" timestamp with time zone not null,
+    "used_at" timestamp with time zone,
+
+    foreign key ("user_id") references "users" ("id"),
+    foreign key ("created_by") references "users" ("id"),
+
+    constraint "password_resets_token_hash_key" unique ("token_hash")
+);
@@ -0,0 +1,17 @@
+drop table "audit_log" cascade;
+
+delete from "password_resets" where "created_by" is null;
+alter table "password_resets" drop constraint "password_resets_created_by_fkey";
+alter table "password_resets" add constraint "password_resets_created_by_fkey"
+    foreign key ("created_by") references "users" ("id");
+alter table "password_resets" alter column "created_by" set not null;
+
+alter table "races" drop constraint "races_winner_id_fkey";
+alter table "races" add constraint "races_winner_id_fkey"
+    foreign key ("winner_id") references "users" ("id");
+delete from "race_participants" where "race_id" in (select "id" from "races" where "created_by" is null);
+delete from "races" where "created_by" is null;
+alter table "races" drop constraint "races_created_by_fkey";
+alter table "races" add constraint "races_created_by_fkey"
+    foreign key ("created_by") references "users" ("id");
+alter table "races" alter column "created_by" set not null;
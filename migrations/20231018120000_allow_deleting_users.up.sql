@@ -0,0 +1,24 @@
+-- Races and password resets outlive the accounts that created or won them
+alter table "races" alter column "created_by" drop not null;
+alter table "races" drop constraint "races_created_by_fkey";
+alter table "races" add constraint "races_created_by_fkey"
+    foreign key ("created_by") references "users" ("id") on delete set null;
+alter table "races" drop constraint "races_winner_id_fkey";
+alter table "races" add constraint "races_winner_id_fkey"
+    foreign key ("winner_id") references "users" ("id") on delete set null;
+
+alter table "password_resets" alter column "created_by" drop not null;
+alter table "password_resets" drop constraint "password_resets_created_by_fkey";
+alter table "password_resets" add constraint "password_resets_created_by_fkey"
+    foreign key ("created_by") references "users" ("id") on delete set null;
+
+-- No foreign key, so the record of an account being deleted survives the account
+create table if not exists "audit_log" (
+    "id" uuid primary key,
+    "user_id" uuid not null,
+    "action" text not null,
+    "detail" text,
+    "created_at" timestamp with time zone not null,
+
+    constraint "audit_log_action_check" check ("action" in ('export', 'delete'))
+);
@@ -0,0 +1,5 @@
+-- Remove 'public_profile' column from 'users'
+alter table "users" drop column "public_profile";
+
+-- Remove 'hide_from_leaderboard' column from 'users'
+alter table "users" drop column "hide_from_leaderboard";
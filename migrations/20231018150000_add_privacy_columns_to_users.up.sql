@@ -0,0 +1,5 @@
+-- Add 'hide_from_leaderboard' column to 'users'
+alter table "users" add column "hide_from_leaderboard" boolean not null default false;
+
+-- Add 'public_profile' column to 'users'
+alter table "users" add column "public_profile" boolean not null default false;
@@ -0,0 +1,2 @@
+-- Remove 'timezone' column from 'users'
+alter table "users" drop column "timezone";
@@ -0,0 +1,2 @@
+-- Add 'timezone' column to 'users'. Users without one follow the server's default time zone.
+alter table "users" add column "timezone" text;
@@ -0,0 +1,2 @@
+-- Remove 'timezone_changed_at' column from 'users'
+alter table "users" drop column "timezone_changed_at";
@@ -0,0 +1,3 @@
+-- Add 'timezone_changed_at' column to 'users', so time zones can't be switched back and forth to
+-- reach puzzles early or solve past days on time. Null until the zone is first changed.
+alter table "users" add column "timezone_changed_at" timestamp with time zone;
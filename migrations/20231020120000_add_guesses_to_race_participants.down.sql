@@ -0,0 +1,2 @@
+-- Remove 'guesses' column from 'race_participants'
+alter table "race_participants" drop column "guesses";
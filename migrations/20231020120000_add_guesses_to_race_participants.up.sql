@@ -0,0 +1,3 @@
+-- Add 'guesses' column to 'race_participants'. Squareword racers guess through the server, which
+-- keeps the solution to itself, so it needs their guesses to know when they've finished.
+alter table "race_participants" add column "guesses" text[] not null default '{}';
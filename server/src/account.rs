@@ -0,0 +1,287 @@
+use axum::{
+    extract::State,
+    http::{header::CONTENT_DISPOSITION, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::PgExecutor;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{error::ApiError, identity::LinkedIdentity, leaderboard, AppState, Game, User};
+
+fn db_error(action: &str) -> impl Fn(sqlx::Error) -> ApiError + '_ {
+    move |e| ApiError::database(action, e)
+}
+
+// Access tokens outlive a deleted account until they expire
+fn not_found() -> ApiError {
+    ApiError::not_found("This account has been deleted")
+}
+
+/// Records an export or deletion. The log has no foreign key to `users`, so it outlives the
+/// account.
+async fn audit<'c>(
+    executor: impl PgExecutor<'c>,
+    user_id: Uuid,
+    action: &str,
+    detail: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    tracing::info!(%user_id, action, detail, "audit");
+
+    sqlx::query(
+        "insert into audit_log (id, user_id, action, detail, created_at) values ($1, $2, $3, $4, $5)",
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(action)
+    .bind(detail)
+    .bind(Utc::now())
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+#[derive(Serialize, sqlx::FromRow, ToSchema)]
+#[schema(as = ExportedProfile)]
+pub struct Profile {
+    id: Uuid,
+    name: String,
+    email: Option<String>,
+    picture: String,
+    provider: String,
+    guest: bool,
+    admin: bool,
+    hide_from_leaderboard: bool,
+    public_profile: bool,
+    timezone: Option<String>,
+    created_at: DateTime<Utc>,
+    last_login: DateTime<Utc>,
+}
+
+#[derive(Serialize, sqlx::FromRow, ToSchema)]
+pub struct ScoreExport {
+    puzzle_id: Uuid,
+    day: NaiveDate,
+    state: Option<String>,
+    winner: bool,
+    timestamp: i64,
+    late: bool,
+    elapsed_seconds: Option<i32>,
+    guess_count: Option<i32>,
+    mistakes: Option<i32>,
+    hints_used: Option<i32>,
+    completed_at: Option<DateTime<Utc>>,
+    version: i64,
+}
+
+#[derive(Serialize, sqlx::FromRow, ToSchema)]
+pub struct MoveExport {
+    game: Game,
+    puzzle_id: Uuid,
+    seq: i32,
+    #[serde(rename = "move")]
+    #[sqlx(rename = "move")]
+    mv: String,
+    client_timestamp: i64,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, sqlx::FromRow, ToSchema)]
+pub struct RaceExport {
+    code: String,
+    game: Game,
+    joined_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
+    progress: i32,
+    finished_at: Option<DateTime<Utc>>,
+    place: Option<i32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Export {
+    exported_at: DateTime<Utc>,
+    profile: Profile,
+    identities: Vec<LinkedIdentity>,
+    sudoku_scores: Vec<ScoreExport>,
+    squareword_scores: Vec<ScoreExport>,
+    moves: Vec<MoveExport>,
+    races: Vec<RaceExport>,
+}
+
+async fn scores(
+    state: &AppState,
+    game: Game,
+    user_id: Uuid,
+) -> Result<Vec<ScoreExport>, sqlx::Error> {
+    sqlx::query_as(&format!(
+        "
+            select
+                s.puzzle_id, p.day, s.state, s.winner, s.timestamp, s.late, s.elapsed_seconds,
+                s.guess_count, s.mistakes, s.hints_used, s.completed_at, s.version
+            from {} s
+            join {} p on p.id = s.puzzle_id
+            where s.user_id = $1
+            order by p.day
+        ",
+        game.scores_table(),
+        game.puzzles_table()
+    ))
+    .bind(user_id)
+    .fetch_all(&state.pool)
+    .await
+}
+
+/// Everything stored about the signed in user, as a JSON download
+#[utoipa::path(
+    get,
+    path = "/me/export",
+    tag = "account",
+    responses(
+        (status = 200, description = "Everything stored about the user, as a download", body = Export),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+        (status = 404, description = "The account has been deleted", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn export(
+    user: User,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let map_err = db_error("exporting account");
+
+    let profile: Profile = sqlx::query_as(
+        "select id, name, email, picture, provider, guest, admin, hide_from_leaderboard, public_profile, timezone, created_at, last_login from users where id = $1",
+    )
+    .bind(user.id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(&map_err)?
+    .ok_or_else(not_found)?;
+
+    let identities = sqlx::query_as(
+        "select provider, email, created_at from user_identities where user_id = $1 order by created_at",
+    )
+    .bind(user.id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(&map_err)?;
+
+    let moves = sqlx::query_as(
+        "
+            select game, puzzle_id, seq, move, client_timestamp, created_at from moves
+            where user_id = $1
+            order by created_at, seq
+        ",
+    )
+    .bind(user.id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(&map_err)?;
+
+    let races = sqlx::query_as(
+        "
+            select r.code, r.game, p.joined_at, r.started_at, p.progress, p.finished_at, p.place
+            from race_participants p
+            join races r on r.id = p.race_id
+            where p.user_id = $1
+            order by p.joined_at
+        ",
+    )
+    .bind(user.id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(&map_err)?;
+
+    let export = Export {
+        exported_at: Utc::now(),
+        profile,
+        identities,
+        sudoku_scores: scores(&state, Game::Sudoku, user.id)
+            .await
+            .map_err(&map_err)?,
+        squareword_scores: scores(&state, Game::Squareword, user.id)
+            .await
+            .map_err(&map_err)?,
+        moves,
+        races,
+    };
+
+    audit(&state.pool, user.id, "export", None)
+        .await
+        .map_err(&map_err)?;
+
+    Ok((
+        [(
+            CONTENT_DISPOSITION,
+            "attachment; filename=\"gotd-export.json\"",
+        )],
+        Json(export),
+    ))
+}
+
+/// Deletes the signed in user and everything they've played. Races they opened or won stay for
+/// the other players, without them.
+#[utoipa::path(
+    delete,
+    path = "/me",
+    tag = "account",
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+        (status = 404, description = "The account has been deleted", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn delete_account(
+    user: User,
+    State(state): State<AppState>,
+) -> Result<StatusCode, ApiError> {
+    let map_err = db_error("deleting account");
+
+    let mut tx = state.pool.begin().await.map_err(&map_err)?;
+
+    let mut removed = Vec::new();
+    for table in [
+        "moves",
+        "sudoku_scores",
+        "squareword_scores",
+        "race_participants",
+        "refresh_tokens",
+        "user_identities",
+        "password_credentials",
+        "password_resets",
+    ] {
+        let rows = sqlx::query(&format!("delete from {} where user_id = $1", table))
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(&map_err)?
+            .rows_affected();
+        removed.push(format!("{} {}", rows, table));
+    }
+
+    let deleted = sqlx::query("delete from users where id = $1")
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(&map_err)?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(not_found());
+    }
+
+    audit(&mut *tx, user.id, "delete", Some(&removed.join(", ")))
+        .await
+        .map_err(&map_err)?;
+
+    tx.commit().await.map_err(&map_err)?;
+
+    // Their wins are gone from the boards
+    leaderboard::notify(&state, Game::Sudoku);
+    leaderboard::notify(&state, Game::Squareword);
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,117 @@
+use axum::{extract::State, Json};
+use chrono::NaiveDate;
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{error::ApiError, AppState, User};
+
+#[derive(Serialize, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CompletionStatus {
+    Unplayed,
+    InProgress,
+    Won,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ArchiveGame {
+    puzzle_id: Uuid,
+    status: CompletionStatus,
+    late: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ArchiveDay {
+    day: NaiveDate,
+    sudoku: Option<ArchiveGame>,
+    squareword: Option<ArchiveGame>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ArchiveResponse {
+    days: Vec<ArchiveDay>,
+}
+
+#[derive(sqlx::FromRow)]
+struct ArchiveRow {
+    day: NaiveDate,
+    sudoku_puzzle_id: Option<Uuid>,
+    sudoku_winner: Option<bool>,
+    sudoku_late: Option<bool>,
+    squareword_puzzle_id: Option<Uuid>,
+    squareword_winner: Option<bool>,
+    squareword_late: Option<bool>,
+}
+
+fn archive_game(
+    puzzle_id: Option<Uuid>,
+    winner: Option<bool>,
+    late: Option<bool>,
+) -> Option<ArchiveGame> {
+    let status = match winner {
+        None => CompletionStatus::Unplayed,
+        Some(false) => CompletionStatus::InProgress,
+        Some(true) => CompletionStatus::Won,
+    };
+
+    puzzle_id.map(|puzzle_id| ArchiveGame {
+        puzzle_id,
+        status,
+        late: late.unwrap_or(false),
+    })
+}
+
+/// Lists every day up to today that has a puzzle, along with the caller's progress on it
+#[utoipa::path(
+    get,
+    path = "/archive",
+    tag = "archive",
+    responses(
+        (status = 200, description = "Every day with a puzzle, newest first", body = ArchiveResponse),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn archive(
+    user: User,
+    State(state): State<AppState>,
+) -> Result<Json<ArchiveResponse>, ApiError> {
+    let today = user.today(&state).await?;
+    let rows: Vec<ArchiveRow> = sqlx::query_as(
+        "
+            select d.day,
+                sp.id as sudoku_puzzle_id, ss.winner as sudoku_winner, ss.late as sudoku_late,
+                qp.id as squareword_puzzle_id, qs.winner as squareword_winner, qs.late as squareword_late
+            from (select day from sudoku_puzzles union select day from squareword_puzzles) d
+            left join sudoku_puzzles sp on sp.day = d.day
+            left join sudoku_scores ss on ss.puzzle_id = sp.id and ss.user_id = $1
+            left join squareword_puzzles qp on qp.day = d.day
+            left join squareword_scores qs on qs.puzzle_id = qp.id and qs.user_id = $1
+            where d.day <= $2
+            order by d.day desc
+        ",
+    )
+    .bind(user.id)
+    .bind(today)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|e| {
+        ApiError::database("querying archive", e)
+    })?;
+
+    let days = rows
+        .into_iter()
+        .map(|row| ArchiveDay {
+            day: row.day,
+            sudoku: archive_game(row.sudoku_puzzle_id, row.sudoku_winner, row.sudoku_late),
+            squareword: archive_game(
+                row.squareword_puzzle_id,
+                row.squareword_winner,
+                row.squareword_late,
+            ),
+        })
+        .collect();
+
+    Ok(Json(ArchiveResponse { days }))
+}
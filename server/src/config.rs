@@ -0,0 +1,568 @@
+use std::{collections::HashSet, fmt, fs, net::SocketAddr, path::PathBuf, str::FromStr};
+
+use axum::http::{header::LINK, HeaderName, HeaderValue};
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+use crate::{
+    error::REQUEST_ID_HEADER,
+    identity::OidcConfig,
+    ratelimit::{IpRange, RateLimit},
+    session::SigningKey,
+};
+
+const DEFAULT_DB_HOST: &str = "localhost";
+const DEFAULT_DB_PORT: u16 = 5432;
+const DEFAULT_DB_NAME: &str = "gotd";
+const DEFAULT_DB_USER: &str = "admin";
+const DEFAULT_DB_PASSWORD: &str = "pgpass";
+const DEFAULT_DB_POOL_SIZE: u32 = 5;
+const DEFAULT_LISTEN: &str = "0.0.0.0:3001";
+const DEFAULT_GOOGLE_CLIENT_ID: &str =
+    "1012807370880-93eor5h650abjrreks9ut5f5dp5tv67q.apps.googleusercontent.com";
+const DEFAULT_GOOGLE_ISSUER: &str = "https://accounts.google.com";
+const DEFAULT_ACCESS_TOKEN_MINUTES: i64 = 15;
+const DEFAULT_REFRESH_TOKEN_DAYS: i64 = 30;
+const DEFAULT_PASSWORD_RESET_HOURS: i64 = 24;
+const DEFAULT_TIMEZONE: Tz = chrono_tz::America::New_York;
+const DEFAULT_PUZZLE_DAYS_AHEAD: i64 = 3;
+// Caps on durations, both to keep sessions and reset links from living forever and because
+// chrono panics on durations past its range
+const MAX_ACCESS_TOKEN_MINUTES: i64 = 24 * 60;
+const MAX_REFRESH_TOKEN_DAYS: i64 = 365;
+const MAX_PASSWORD_RESET_HOURS: i64 = 7 * 24;
+const MAX_PUZZLE_DAYS_AHEAD: i64 = 365;
+const DEFAULT_RATE_LIMIT_AUTH: &str = "10/min";
+const DEFAULT_RATE_LIMIT_WRITE: &str = "120/min";
+const DEFAULT_RATE_LIMIT_READ: &str = "600/min";
+
+/// Serves the gotd API. Every flag can also be set with the environment variable shown below, or in
+/// the TOML file given with `--config` using the flag's name with underscores. Flags beat the
+/// environment, which beats the file, which beats the defaults.
+#[derive(Parser, Debug)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// A TOML file of settings
+    #[arg(long, env = "GOTD_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[arg(long, env = "GOTD_DB_HOST")]
+    db_host: Option<String>,
+
+    #[arg(long, env = "GOTD_DB_PORT")]
+    db_port: Option<u16>,
+
+    #[arg(long, env = "GOTD_DB_NAME")]
+    db_name: Option<String>,
+
+    #[arg(long, env = "GOTD_DB_USER")]
+    db_user: Option<String>,
+
+    #[arg(long, env = "GOTD_DB_PASSWORD", hide_env_values = true)]
+    db_password: Option<String>,
+
+    #[arg(long, env = "GOTD_DB_POOL_SIZE")]
+    db_pool_size: Option<u32>,
+
+    /// The address to serve on
+    #[arg(long, env = "GOTD_LISTEN")]
+    listen: Option<SocketAddr>,
+
+    /// Origins allowed to call the server from a browser, or `*` for any
+    #[arg(long = "cors-origin", env = "GOTD_CORS_ORIGINS", value_delimiter = ',')]
+    cors_origins: Vec<String>,
+
+    #[arg(long, env = "GOTD_TEST_SUDOKU", num_args = 0..=1, default_missing_value = "true")]
+    test_sudoku: Option<bool>,
+
+    /// Keys for session tokens, as `kid:secret`. The first one signs new tokens, and the rest can
+    /// still verify tokens signed before a rotation.
+    #[arg(
+        long = "jwt-key",
+        env = "GOTD_JWT_KEYS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    jwt_keys: Vec<SigningKey>,
+
+    #[arg(long, env = "GOTD_GOOGLE_CLIENT_ID")]
+    google_client_id: Option<String>,
+
+    #[arg(long, env = "GOTD_GOOGLE_ISSUER")]
+    google_issuer: Option<String>,
+
+    /// More OpenID Connect providers to allow sign in with, as `name,issuer,client_id`
+    #[arg(
+        long = "oidc-provider",
+        env = "GOTD_OIDC_PROVIDERS",
+        value_delimiter = ';'
+    )]
+    oidc_providers: Vec<OidcConfig>,
+
+    #[arg(long, env = "GOTD_ACCESS_TOKEN_MINUTES")]
+    access_token_minutes: Option<i64>,
+
+    #[arg(long, env = "GOTD_REFRESH_TOKEN_DAYS")]
+    refresh_token_days: Option<i64>,
+
+    /// Allows registering and signing in with an email address and password, for deployments that
+    /// can't reach an identity provider
+    #[arg(long, env = "GOTD_PASSWORD_ACCOUNTS", num_args = 0..=1, default_missing_value = "true")]
+    password_accounts: Option<bool>,
+
+    #[arg(long, env = "GOTD_PASSWORD_RESET_HOURS")]
+    password_reset_hours: Option<i64>,
+
+    /// The time zone whose midnight starts a new day for users who haven't picked one, like
+    /// `Europe/London`
+    #[arg(long, env = "GOTD_DEFAULT_TIMEZONE")]
+    default_timezone: Option<Tz>,
+
+    /// How many days past today to keep puzzles generated for
+    #[arg(long, env = "GOTD_PUZZLE_DAYS_AHEAD")]
+    puzzle_days_ahead: Option<i64>,
+
+    /// The date the unversioned API paths are due to be removed, announced to clients that still
+    /// call them, like `2024-06-30`
+    #[arg(long, env = "GOTD_LEGACY_API_SUNSET")]
+    legacy_api_sunset: Option<NaiveDate>,
+
+    /// Requests each user or address can make to the sign in routes, like `10/min`
+    #[arg(long, env = "GOTD_RATE_LIMIT_AUTH")]
+    rate_limit_auth: Option<RateLimit>,
+
+    /// Requests each user or address can make to the other routes that change something
+    #[arg(long, env = "GOTD_RATE_LIMIT_WRITE")]
+    rate_limit_write: Option<RateLimit>,
+
+    /// Requests each user or address can make to the routes that only read
+    #[arg(long, env = "GOTD_RATE_LIMIT_READ")]
+    rate_limit_read: Option<RateLimit>,
+
+    /// Proxies in front of the server, as addresses or CIDR blocks, whose `X-Forwarded-For` is
+    /// believed when working out who a request came from
+    #[arg(
+        long = "trusted-proxy",
+        env = "GOTD_TRUSTED_PROXIES",
+        value_delimiter = ','
+    )]
+    trusted_proxies: Vec<IpRange>,
+
+    /// The address to serve Prometheus metrics on, at `/metrics`. Keep it off the public internet.
+    /// Without it, metrics aren't served.
+    #[arg(long, env = "GOTD_METRICS_LISTEN")]
+    metrics_listen: Option<SocketAddr>,
+}
+
+#[derive(Subcommand, Debug, Clone, Copy)]
+pub enum Command {
+    /// Prints the OpenAPI document for the API as JSON, for generating clients
+    Openapi,
+}
+
+/// The `--config` file. Keys and providers are strings in the same format as on the command line.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    db_host: Option<String>,
+    db_port: Option<u16>,
+    db_name: Option<String>,
+    db_user: Option<String>,
+    db_password: Option<String>,
+    db_pool_size: Option<u32>,
+    listen: Option<SocketAddr>,
+    cors_origins: Option<Vec<String>>,
+    test_sudoku: Option<bool>,
+    jwt_keys: Option<Vec<String>>,
+    google_client_id: Option<String>,
+    google_issuer: Option<String>,
+    oidc_providers: Option<Vec<String>>,
+    access_token_minutes: Option<i64>,
+    refresh_token_days: Option<i64>,
+    password_accounts: Option<bool>,
+    password_reset_hours: Option<i64>,
+    default_timezone: Option<String>,
+    puzzle_days_ahead: Option<i64>,
+    legacy_api_sunset: Option<String>,
+    rate_limit_auth: Option<String>,
+    rate_limit_write: Option<String>,
+    rate_limit_read: Option<String>,
+    trusted_proxies: Option<Vec<String>>,
+    metrics_listen: Option<SocketAddr>,
+}
+
+/// A value kept out of logs of the configuration
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(..)")
+    }
+}
+
+/// The server's settings, with every layer applied and checked
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub db_host: String,
+    pub db_port: u16,
+    pub db_name: String,
+    pub db_user: String,
+    pub db_password: Secret,
+    pub db_pool_size: u32,
+    pub listen: SocketAddr,
+    /// Empty means any origin
+    pub cors_origins: Vec<HeaderValue>,
+    pub test_sudoku: bool,
+    pub jwt_keys: Vec<SigningKey>,
+    pub google_client_id: String,
+    pub google_issuer: String,
+    pub oidc_providers: Vec<OidcConfig>,
+    pub access_token_minutes: i64,
+    pub refresh_token_days: i64,
+    pub password_accounts: bool,
+    pub password_reset_hours: i64,
+    pub default_timezone: Tz,
+    pub puzzle_days_ahead: i64,
+    /// When the unversioned paths go away, if that's been decided
+    pub legacy_api_sunset: Option<NaiveDate>,
+    pub rate_limit_auth: RateLimit,
+    pub rate_limit_write: RateLimit,
+    pub rate_limit_read: RateLimit,
+    pub trusted_proxies: Vec<IpRange>,
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+/// Parses each of a file's entries, noting the ones that don't parse
+fn parse_all<T: FromStr<Err = String>>(
+    name: &str,
+    values: Option<Vec<String>>,
+    errors: &mut Vec<String>,
+) -> Vec<T> {
+    values
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|value| match value.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(e) => {
+                errors.push(format!("{}: {}", name, e));
+                None
+            }
+        })
+        .collect()
+}
+
+/// Picks a rate limit from the arguments, then the file, then the default
+fn rate_limit(
+    name: &str,
+    arg: Option<RateLimit>,
+    file: Option<String>,
+    default: &str,
+    errors: &mut Vec<String>,
+) -> RateLimit {
+    let fallback = || default.parse().expect("the default rate limits are valid");
+    match (arg, file) {
+        (Some(limit), _) => limit,
+        (None, Some(limit)) => limit.parse().unwrap_or_else(|e| {
+            errors.push(format!("{}: {}", name, e));
+            fallback()
+        }),
+        (None, None) => fallback(),
+    }
+}
+
+fn read_file(path: &PathBuf, errors: &mut Vec<String>) -> FileConfig {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            errors.push(format!("can't read {}: {}", path.display(), e));
+            return FileConfig::default();
+        }
+    };
+
+    toml::from_str(&text).unwrap_or_else(|e| {
+        errors.push(format!("{} is invalid: {}", path.display(), e));
+        FileConfig::default()
+    })
+}
+
+fn non_empty(name: &str, value: String, errors: &mut Vec<String>) -> String {
+    if value.trim().is_empty() {
+        errors.push(format!("{} must not be empty", name));
+    }
+    value
+}
+
+fn up_to(name: &str, value: i64, max: i64, errors: &mut Vec<String>) -> i64 {
+    if !(1..=max).contains(&value) {
+        errors.push(format!("{} must be from 1 to {}, not {}", name, max, value));
+    }
+    value
+}
+
+fn cors_origins(origins: Vec<String>, errors: &mut Vec<String>) -> Vec<HeaderValue> {
+    if origins.iter().any(|o| o == "*") {
+        if origins.len() > 1 {
+            errors.push("cors_origins can't mix * with specific origins".to_string());
+        }
+        return Vec::new();
+    }
+
+    origins
+        .iter()
+        .filter_map(|origin| {
+            let origin = origin.trim().trim_end_matches('/');
+            let valid = origin.starts_with("https://") || origin.starts_with("http://");
+            match HeaderValue::from_str(origin) {
+                Ok(value) if valid => Some(value),
+                _ => {
+                    errors.push(format!(
+                        "cors_origins: {} isn't an http or https origin",
+                        origin
+                    ));
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+impl Config {
+    /// Reads the configuration, returning every problem with it rather than just the first
+    pub fn load(args: Args) -> Result<Config, Vec<String>> {
+        let mut errors = Vec::new();
+
+        let file = match &args.config {
+            Some(path) => read_file(path, &mut errors),
+            None => FileConfig::default(),
+        };
+
+        // Lists from the command line or environment replace the file's rather than adding to them
+        let jwt_keys = if args.jwt_keys.is_empty() {
+            parse_all("jwt_keys", file.jwt_keys, &mut errors)
+        } else {
+            args.jwt_keys
+        };
+        let oidc_providers = if args.oidc_providers.is_empty() {
+            parse_all("oidc_providers", file.oidc_providers, &mut errors)
+        } else {
+            args.oidc_providers
+        };
+        let trusted_proxies = if args.trusted_proxies.is_empty() {
+            parse_all("trusted_proxies", file.trusted_proxies, &mut errors)
+        } else {
+            args.trusted_proxies
+        };
+        let cors_origins = cors_origins(
+            if args.cors_origins.is_empty() {
+                file.cors_origins.unwrap_or_else(|| vec!["*".to_string()])
+            } else {
+                args.cors_origins
+            },
+            &mut errors,
+        );
+
+        if jwt_keys.is_empty() {
+            errors.push(
+                "at least one session key is required, from --jwt-key, GOTD_JWT_KEYS or jwt_keys"
+                    .to_string(),
+            );
+        }
+        let mut kids = HashSet::new();
+        for key in &jwt_keys {
+            if !kids.insert(key.kid()) {
+                errors.push(format!("session key {} is given more than once", key.kid()));
+            }
+        }
+
+        let mut providers = HashSet::new();
+        for provider in &oidc_providers {
+            if !providers.insert(provider.name()) {
+                errors.push(format!(
+                    "identity provider {} is given more than once",
+                    provider.name()
+                ));
+            }
+        }
+
+        let db_pool_size = args
+            .db_pool_size
+            .or(file.db_pool_size)
+            .unwrap_or(DEFAULT_DB_POOL_SIZE);
+        if db_pool_size == 0 {
+            errors.push("db_pool_size must be at least 1".to_string());
+        }
+
+        let google_issuer = args
+            .google_issuer
+            .or(file.google_issuer)
+            .unwrap_or_else(|| DEFAULT_GOOGLE_ISSUER.to_string());
+        if !google_issuer.starts_with("https://") && !google_issuer.starts_with("http://") {
+            errors.push(format!(
+                "google_issuer {} must be an http or https URL",
+                google_issuer
+            ));
+        }
+
+        let default_timezone = match (args.default_timezone, file.default_timezone) {
+            (Some(timezone), _) => timezone,
+            (None, Some(name)) => name.parse().unwrap_or_else(|e| {
+                errors.push(format!("default_timezone: {}", e));
+                DEFAULT_TIMEZONE
+            }),
+            (None, None) => DEFAULT_TIMEZONE,
+        };
+
+        let legacy_api_sunset = match (args.legacy_api_sunset, file.legacy_api_sunset) {
+            (Some(date), _) => Some(date),
+            (None, Some(date)) => date
+                .parse()
+                .map_err(|e| errors.push(format!("legacy_api_sunset: {}", e)))
+                .ok(),
+            (None, None) => None,
+        };
+
+        let config = Config {
+            db_host: non_empty(
+                "db_host",
+                args.db_host
+                    .or(file.db_host)
+                    .unwrap_or_else(|| DEFAULT_DB_HOST.to_string()),
+                &mut errors,
+            ),
+            db_port: args.db_port.or(file.db_port).unwrap_or(DEFAULT_DB_PORT),
+            db_name: non_empty(
+                "db_name",
+                args.db_name
+                    .or(file.db_name)
+                    .unwrap_or_else(|| DEFAULT_DB_NAME.to_string()),
+                &mut errors,
+            ),
+            db_user: non_empty(
+                "db_user",
+                args.db_user
+                    .or(file.db_user)
+                    .unwrap_or_else(|| DEFAULT_DB_USER.to_string()),
+                &mut errors,
+            ),
+            db_password: Secret(
+                args.db_password
+                    .or(file.db_password)
+                    .unwrap_or_else(|| DEFAULT_DB_PASSWORD.to_string()),
+            ),
+            db_pool_size,
+            listen: args.listen.or(file.listen).unwrap_or_else(|| {
+                DEFAULT_LISTEN
+                    .parse()
+                    .expect("the default listen address is valid")
+            }),
+            cors_origins,
+            test_sudoku: args.test_sudoku.or(file.test_sudoku).unwrap_or(false),
+            jwt_keys,
+            google_client_id: non_empty(
+                "google_client_id",
+                args.google_client_id
+                    .or(file.google_client_id)
+                    .unwrap_or_else(|| DEFAULT_GOOGLE_CLIENT_ID.to_string()),
+                &mut errors,
+            ),
+            google_issuer,
+            oidc_providers,
+            access_token_minutes: up_to(
+                "access_token_minutes",
+                args.access_token_minutes
+                    .or(file.access_token_minutes)
+                    .unwrap_or(DEFAULT_ACCESS_TOKEN_MINUTES),
+                MAX_ACCESS_TOKEN_MINUTES,
+                &mut errors,
+            ),
+            refresh_token_days: up_to(
+                "refresh_token_days",
+                args.refresh_token_days
+                    .or(file.refresh_token_days)
+                    .unwrap_or(DEFAULT_REFRESH_TOKEN_DAYS),
+                MAX_REFRESH_TOKEN_DAYS,
+                &mut errors,
+            ),
+            password_accounts: args
+                .password_accounts
+                .or(file.password_accounts)
+                .unwrap_or(false),
+            password_reset_hours: up_to(
+                "password_reset_hours",
+                args.password_reset_hours
+                    .or(file.password_reset_hours)
+                    .unwrap_or(DEFAULT_PASSWORD_RESET_HOURS),
+                MAX_PASSWORD_RESET_HOURS,
+                &mut errors,
+            ),
+            default_timezone,
+            puzzle_days_ahead: up_to(
+                "puzzle_days_ahead",
+                args.puzzle_days_ahead
+                    .or(file.puzzle_days_ahead)
+                    .unwrap_or(DEFAULT_PUZZLE_DAYS_AHEAD),
+                MAX_PUZZLE_DAYS_AHEAD,
+                &mut errors,
+            ),
+            legacy_api_sunset,
+            rate_limit_auth: rate_limit(
+                "rate_limit_auth",
+                args.rate_limit_auth,
+                file.rate_limit_auth,
+                DEFAULT_RATE_LIMIT_AUTH,
+                &mut errors,
+            ),
+            rate_limit_write: rate_limit(
+                "rate_limit_write",
+                args.rate_limit_write,
+                file.rate_limit_write,
+                DEFAULT_RATE_LIMIT_WRITE,
+                &mut errors,
+            ),
+            rate_limit_read: rate_limit(
+                "rate_limit_read",
+                args.rate_limit_read,
+                file.rate_limit_read,
+                DEFAULT_RATE_LIMIT_READ,
+                &mut errors,
+            ),
+            trusted_proxies,
+            metrics_listen: args.metrics_listen.or(file.metrics_listen),
+        };
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn cors(&self) -> CorsLayer {
+        if self.cors_origins.is_empty() {
+            return CorsLayer::permissive();
+        }
+
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(self.cors_origins.clone()))
+            .allow_methods(Any)
+            .allow_headers(Any)
+            .expose_headers([
+                HeaderName::from_static(REQUEST_ID_HEADER),
+                HeaderName::from_static("deprecation"),
+                HeaderName::from_static("sunset"),
+                LINK,
+            ])
+    }
+}
@@ -0,0 +1,627 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    response::Response,
+};
+use chrono::{NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    gamestate::{check_notes, InputStyle, SudokuCell, SudokuSnapshot, SudokuState},
+    leaderboard, store_score, telemetry, today_in, AppState, CheckedState, Game, ScoreSave, User,
+};
+
+const MAX_ROOM_NAME: usize = 32;
+const BROADCAST_CAPACITY: usize = 256;
+
+/// Rooms are keyed by puzzle and room name, so a room name can be reused every day
+pub type Rooms = Arc<Mutex<HashMap<(Uuid, String), Room>>>;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CoopCell {
+    value: Option<u8>,
+    notes: Vec<u8>,
+    is_given: bool,
+    /// Sequence number of the last edit to this cell, 0 if it has never been edited
+    seq: u64,
+    updated_by: Option<Uuid>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Participant {
+    user_id: Uuid,
+    name: String,
+    connected: bool,
+}
+
+struct Member {
+    name: String,
+    /// Whether the save counts as on the day depends on each member's own time zone
+    zone: Tz,
+    connections: usize,
+    /// The version of the member's own score the room may replace. Only progress that came from
+    /// the room, or that there wasn't any of, is replaced before the puzzle is solved.
+    claim: Option<i64>,
+}
+
+pub struct Room {
+    day: NaiveDate,
+    puzzle: String,
+    solution: Vec<u8>,
+    cells: Vec<CoopCell>,
+    seq: u64,
+    /// Everyone who has joined, including people who have since left, since they all get credit
+    members: HashMap<Uuid, Member>,
+    base_seconds: u32,
+    started: Instant,
+    solved_seconds: Option<u32>,
+    sender: broadcast::Sender<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Sets or clears a cell's value. `seen` is the cell's `seq` when the client made the edit.
+    Value {
+        cell: usize,
+        value: Option<u8>,
+        seen: u64,
+    },
+    Notes {
+        cell: usize,
+        notes: Vec<u8>,
+        seen: u64,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Snapshot {
+        puzzle_id: Uuid,
+        seq: u64,
+        cells: Vec<CoopCell>,
+        participants: Vec<Participant>,
+        solved: bool,
+    },
+    Edit {
+        cell: usize,
+        #[serde(flatten)]
+        state: CoopCell,
+    },
+    Rejected {
+        cell: usize,
+        reason: String,
+        current: Option<CoopCell>,
+    },
+    Joined {
+        user_id: Uuid,
+        name: String,
+    },
+    Left {
+        user_id: Uuid,
+    },
+    Solved {
+        seconds: u32,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl ServerMessage {
+    fn to_text(&self) -> String {
+        serde_json::to_string(self).expect("coop messages always serialize")
+    }
+}
+
+impl Room {
+    /// Starts a room on the puzzle's givens, carrying over the first player's own progress if they
+    /// have any
+    fn new(puzzle: &PuzzleRow, seed: Option<&SudokuState>) -> Room {
+        let seed_cells = seed
+            .and_then(|s| s.history.as_ref())
+            .and_then(|h| h.last())
+            .map(|s| s.cells.as_slice())
+            .unwrap_or_default();
+
+        let cells = puzzle
+            .puzzle
+            .chars()
+            .enumerate()
+            .map(|(i, c)| match c.to_digit(10) {
+                Some(given) => CoopCell {
+                    value: Some(given as u8),
+                    notes: Vec::new(),
+                    is_given: true,
+                    seq: 0,
+                    updated_by: None,
+                },
+                None => {
+                    let seeded = seed_cells.get(i);
+                    CoopCell {
+                        value: seeded.and_then(|c| c.value),
+                        notes: seeded.map(|c| c.notes.clone()).unwrap_or_default(),
+                        is_given: false,
+                        seq: 0,
+                        updated_by: None,
+                    }
+                }
+            })
+            .collect();
+
+        Room {
+            day: puzzle.day,
+            puzzle: puzzle.puzzle.clone(),
+            solution: puzzle.solution.bytes().map(|b| b - b'0').collect(),
+            cells,
+            seq: 0,
+            members: HashMap::new(),
+            base_seconds: seed.map_or(0, |s| s.seconds),
+            started: Instant::now(),
+            solved_seconds: None,
+            sender: broadcast::channel(BROADCAST_CAPACITY).0,
+        }
+    }
+
+    fn seconds(&self) -> u32 {
+        self.solved_seconds.unwrap_or_else(|| {
+            let elapsed = u32::try_from(self.started.elapsed().as_secs()).unwrap_or(u32::MAX);
+            self.base_seconds.saturating_add(elapsed)
+        })
+    }
+
+    fn connected(&self) -> usize {
+        self.members.values().map(|m| m.connections).sum()
+    }
+
+    fn snapshot(&self, puzzle_id: Uuid) -> ServerMessage {
+        ServerMessage::Snapshot {
+            puzzle_id,
+            seq: self.seq,
+            cells: self.cells.clone(),
+            participants: self
+                .members
+                .iter()
+                .map(|(user_id, m)| Participant {
+                    user_id: *user_id,
+                    name: m.name.clone(),
+                    connected: m.connections > 0,
+                })
+                .collect(),
+            solved: self.solved_seconds.is_some(),
+        }
+    }
+
+    fn broadcast(&self, message: ServerMessage) {
+        // Only fails when nobody is subscribed, in which case there is no one to tell
+        let _ = self.sender.send(message.to_text());
+    }
+
+    fn join(&mut self, user: &User, zone: Tz, claim: Option<i64>) {
+        let member = self.members.entry(user.id).or_insert_with(|| Member {
+            name: user.name.clone(),
+            zone,
+            connections: 0,
+            claim,
+        });
+        member.connections += 1;
+
+        if member.connections == 1 {
+            self.broadcast(ServerMessage::Joined {
+                user_id: user.id,
+                name: user.name.clone(),
+            });
+        }
+    }
+
+    fn leave(&mut self, user_id: Uuid) {
+        if let Some(member) = self.members.get_mut(&user_id) {
+            member.connections = member.connections.saturating_sub(1);
+            if member.connections == 0 {
+                self.broadcast(ServerMessage::Left { user_id });
+            }
+        }
+    }
+
+    /// Applies an edit, broadcasting it to the room. Edits are ordered by the room's sequence
+    /// number, and an edit made against an older version of a cell than the room now has is
+    /// rejected, so a player never silently overwrites a change they haven't seen.
+    fn apply(&mut self, user_id: Uuid, message: ClientMessage) -> Result<(), ServerMessage> {
+        let (cell, seen) = match &message {
+            ClientMessage::Value { cell, seen, .. } | ClientMessage::Notes { cell, seen, .. } => {
+                (*cell, *seen)
+            }
+        };
+        let reject = |reason: &str, current: Option<CoopCell>| ServerMessage::Rejected {
+            cell,
+            reason: reason.to_string(),
+            current,
+        };
+
+        if self.solved_seconds.is_some() {
+            return Err(reject("the puzzle is already solved", None));
+        }
+        let Some(current) = self.cells.get(cell) else {
+            return Err(reject("no such cell", None));
+        };
+        if current.is_given {
+            return Err(reject(
+                "given cells can't be changed",
+                Some(current.clone()),
+            ));
+        }
+        if current.seq != seen {
+            return Err(reject(
+                "the cell was changed by someone else",
+                Some(current.clone()),
+            ));
+        }
+
+        let (value, notes) = match message {
+            ClientMessage::Value { value, .. } => {
+                if value.is_some_and(|v| !(1..=9).contains(&v)) {
+                    return Err(reject("value must be between 1 and 9", None));
+                }
+                (value, current.notes.clone())
+            }
+            ClientMessage::Notes { notes, .. } => {
+                if let Some(message) = check_notes(&notes) {
+                    return Err(reject(&format!("notes {}", message), None));
+                }
+                (current.value, notes)
+            }
+        };
+
+        self.seq += 1;
+        let updated = CoopCell {
+            value,
+            notes,
+            is_given: false,
+            seq: self.seq,
+            updated_by: Some(user_id),
+        };
+        self.cells[cell] = updated.clone();
+        self.broadcast(ServerMessage::Edit {
+            cell,
+            state: updated,
+        });
+
+        let solved = self
+            .cells
+            .iter()
+            .zip(&self.solution)
+            .all(|(c, s)| c.value == Some(*s));
+        if solved {
+            let seconds = self.seconds();
+            self.solved_seconds = Some(seconds);
+            self.broadcast(ServerMessage::Solved { seconds });
+        }
+
+        Ok(())
+    }
+
+    /// The room's grid as a regular sudoku state, so it can be saved like any other
+    fn state(&self, puzzle_id: Uuid) -> SudokuState {
+        SudokuState {
+            id: Some(puzzle_id),
+            paused: false,
+            seconds: self.seconds(),
+            input_style: InputStyle::Number,
+            history: Some(vec![SudokuSnapshot {
+                selected_cell: None,
+                cells: self
+                    .cells
+                    .iter()
+                    .map(|c| SudokuCell {
+                        value: c.value,
+                        is_given: c.is_given,
+                        check: None,
+                        notes: c.notes.clone(),
+                    })
+                    .collect(),
+            }]),
+            puzzle_day: self.day.and_hms_opt(0, 0, 0).map(|d| d.and_utc()),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct PuzzleRow {
+    puzzle: String,
+    solution: String,
+    day: NaiveDate,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct CoopQuery {
+    room: String,
+    /// Browsers can't set headers on a WebSocket handshake, so the session token comes in the
+    /// query string instead
+    token: String,
+}
+
+/// Saves the room's grid for its members through the same path as a regular save, so moves are
+/// logged and versions move on. Until the puzzle is solved, members' own progress is only replaced
+/// where the room has a claim on it. A solve is saved for everyone, except scores already won.
+async fn persist(state: &AppState, key: &(Uuid, String)) -> Result<(), ApiError> {
+    let (members, game_state, puzzle, winner) = {
+        let rooms = state.rooms.lock().unwrap();
+        let Some(room) = rooms.get(key) else {
+            return Ok(());
+        };
+        (
+            room.members
+                .iter()
+                .map(|(&user_id, member)| (user_id, room.day < today_in(member.zone), member.claim))
+                .collect::<Vec<_>>(),
+            room.state(key.0),
+            room.puzzle.clone(),
+            room.solved_seconds.is_some(),
+        )
+    };
+
+    let timestamp = Utc::now().timestamp_millis();
+    let mut saved = Vec::new();
+
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|e| ApiError::database("starting transaction", e))?;
+    for &(user_id, late, claim) in &members {
+        if claim.is_none() && !winner {
+            continue;
+        }
+        let version = store_score(
+            &mut tx,
+            ScoreSave {
+                game: Game::Sudoku,
+                user_id,
+                puzzle_id: key.0,
+                state: Some(CheckedState::Sudoku {
+                    state: &game_state,
+                    puzzle: &puzzle,
+                }),
+                timestamp,
+                winner,
+                late,
+                version: if winner { None } else { claim },
+            },
+        )
+        .await?;
+        saved.push((user_id, late, version));
+    }
+    tx.commit()
+        .await
+        .map_err(|e| ApiError::database("saving co-op sudoku", e))?;
+
+    // A member who saved on their own since the room last did has taken their progress back
+    if let Some(room) = state.rooms.lock().unwrap().get_mut(key) {
+        for &(user_id, _, version) in &saved {
+            if let Some(member) = room.members.get_mut(&user_id) {
+                member.claim = version;
+            }
+        }
+    }
+
+    if winner {
+        let won: Vec<bool> = saved
+            .iter()
+            .filter(|(_, _, version)| version.is_some())
+            .map(|&(_, late, _)| late)
+            .collect();
+        for &late in &won {
+            telemetry::record_solve(Game::Sudoku, late);
+        }
+        if won.iter().any(|late| !late) {
+            leaderboard::notify(state, Game::Sudoku);
+        }
+    }
+
+    Ok(())
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> Result<(), axum::Error> {
+    socket.send(Message::Text(message.to_text())).await
+}
+
+/// Someone connecting to a room, with their saved progress on its puzzle
+struct Joining {
+    user: User,
+    zone: Tz,
+    saved: Option<SudokuState>,
+    version: Option<i64>,
+}
+
+async fn run(
+    mut socket: WebSocket,
+    state: AppState,
+    key: (Uuid, String),
+    puzzle: PuzzleRow,
+    joining: Joining,
+) {
+    let Joining {
+        user,
+        zone,
+        saved,
+        version,
+    } = joining;
+    let (mut receiver, snapshot) = {
+        let mut rooms = state.rooms.lock().unwrap();
+        // Whoever opens the room brings their progress into it, so the room can go on saving it
+        let opened = !rooms.contains_key(&key);
+        let room = rooms
+            .entry(key.clone())
+            .or_insert_with(|| Room::new(&puzzle, saved.as_ref()));
+        let claim = (opened || saved.is_none()).then_some(version.unwrap_or(0));
+        room.join(&user, zone, claim);
+        (room.sender.subscribe(), room.snapshot(key.0))
+    };
+
+    if send(&mut socket, &snapshot).await.is_ok() {
+        loop {
+            tokio::select! {
+                incoming = socket.recv() => {
+                    let text = match incoming {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => continue,
+                    };
+
+                    let result = match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(message) => {
+                            let mut rooms = state.rooms.lock().unwrap();
+                            match rooms.get_mut(&key) {
+                                Some(room) => room
+                                    .apply(user.id, message)
+                                    .map(|_| room.solved_seconds.is_some()),
+                                None => break,
+                            }
+                        }
+                        Err(e) => Err(ServerMessage::Error {
+                            message: format!("Invalid message: {}", e),
+                        }),
+                    };
+
+                    match result {
+                        Ok(true) => {
+                            if let Err(e) = persist(&state, &key).await {
+                                tracing::error!("Failed saving co-op sudoku {}: {:?}", key.1, e);
+                            }
+                        }
+                        Ok(false) => {}
+                        Err(reply) => {
+                            if send(&mut socket, &reply).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                broadcast = receiver.recv() => {
+                    let sent = match broadcast {
+                        Ok(text) => socket.send(Message::Text(text)).await,
+                        // Too far behind to replay what was missed, so start over from the room's
+                        // current state
+                        Err(RecvError::Lagged(_)) => {
+                            let snapshot = match state.rooms.lock().unwrap().get(&key) {
+                                Some(room) => room.snapshot(key.0),
+                                None => break,
+                            };
+                            send(&mut socket, &snapshot).await
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    if sent.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    let empty = {
+        let mut rooms = state.rooms.lock().unwrap();
+        match rooms.get_mut(&key) {
+            Some(room) => {
+                room.leave(user.id);
+                room.connected() == 0
+            }
+            None => false,
+        }
+    };
+
+    // Once everyone has gone, save where the room got to and close it. Someone may have rejoined
+    // while saving, in which case the room stays open.
+    if empty {
+        if let Err(e) = persist(&state, &key).await {
+            tracing::error!("Failed saving co-op sudoku {}: {:?}", key.1, e);
+        }
+
+        let mut rooms = state.rooms.lock().unwrap();
+        if rooms.get(&key).is_some_and(|room| room.connected() == 0) {
+            rooms.remove(&key);
+        }
+    }
+}
+
+/// Joins a shared room for solving a sudoku together. The socket first receives a `snapshot` of
+/// the room, then every `edit` made by anyone in it.
+#[utoipa::path(
+    get,
+    path = "/sudoku/coop/{puzzle_id}",
+    tag = "sudoku",
+    params(("puzzle_id" = Uuid, Path, description = "The puzzle's id"), CoopQuery),
+    responses(
+        (status = 101, description = "A WebSocket shared by everyone in the room"),
+        (status = 404, description = "No such puzzle", body = ErrorBody),
+    ),
+)]
+pub async fn coop(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Path(puzzle_id): Path<Uuid>,
+    Query(query): Query<CoopQuery>,
+) -> Result<Response, ApiError> {
+    let user = state.sessions.authenticate(&query.token)?;
+
+    if query.room.is_empty()
+        || query.room.len() > MAX_ROOM_NAME
+        || !query
+            .room
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(ApiError::bad_request(format!(
+            "Room names must be 1 to {} letters, digits, '-' or '_'",
+            MAX_ROOM_NAME
+        )));
+    }
+
+    let puzzle: Option<PuzzleRow> =
+        sqlx::query_as("select puzzle, solution, day from sudoku_puzzles where id = $1")
+            .bind(puzzle_id)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(|e| ApiError::database("querying sudoku puzzle", e))?;
+    let zone = user.zone(&state).await?;
+    let today = today_in(zone);
+    let puzzle = puzzle
+        .filter(|p| p.day <= today)
+        .ok_or_else(|| ApiError::not_found(format!("No sudoku puzzle with id {}", puzzle_id)))?;
+
+    let saved: Option<(Option<String>, i64)> = sqlx::query_as(
+        "select state, version from sudoku_scores where user_id = $1 and puzzle_id = $2",
+    )
+    .bind(user.id)
+    .bind(puzzle_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| ApiError::database("querying sudoku score", e))?;
+    let version = saved.as_ref().map(|(_, version)| *version);
+    let saved: Option<SudokuState> = saved
+        .and_then(|(raw, _)| raw)
+        .and_then(|raw| serde_json::from_str(&raw).ok());
+
+    let key = (puzzle_id, query.room);
+    let joining = Joining {
+        user,
+        zone,
+        saved,
+        version,
+    };
+    Ok(ws.on_upgrade(move |socket| run(socket, state, key, puzzle, joining)))
+}
@@ -0,0 +1,166 @@
+use std::fmt::Display;
+
+use axum::{
+    http::{HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use tracing::Instrument;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Tags each request with an id, taken from the caller's `X-Request-Id` or made up, so an error a
+/// player reports can be found in the logs. The id is echoed back on every response.
+pub async fn request_id<B>(request: Request<B>, next: Next<B>) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 64)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let span = tracing::info_span!("request", request_id = %id);
+    let mut response = REQUEST_ID
+        .scope(id.clone(), next.run(request).instrument(span))
+        .await;
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(String::clone).ok()
+}
+
+/// An error for the client. `message` is safe to show to a player, anything more detailed only
+/// goes to the logs.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+/// What every error response carries
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody<'a> {
+    /// A stable identifier for the kind of error, like `not_found` or `invalid_state`
+    code: &'a str,
+    message: &'a str,
+    /// Matches the `X-Request-Id` response header
+    request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> ApiError {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::CONFLICT, "conflict", message)
+    }
+
+    /// Something went wrong on our side while `action`. The client only hears that it failed.
+    pub fn internal(action: &str, error: impl Display) -> ApiError {
+        tracing::error!("Failed {}: {}", action, error);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            format!("Failed {}", action),
+        )
+    }
+
+    /// Maps a database error while `action` to a status the client can do something about.
+    /// Constraint violations usually mean the request raced another one or referred to something
+    /// that's gone.
+    pub fn database(action: &str, error: sqlx::Error) -> ApiError {
+        let constraint = error
+            .as_database_error()
+            .and_then(|e| e.code())
+            .map(|code| code.into_owned());
+
+        match (&error, constraint.as_deref()) {
+            (sqlx::Error::RowNotFound, _) => {
+                ApiError::not_found(format!("Failed {}: not found", action))
+            }
+            // unique_violation
+            (_, Some("23505")) => {
+                tracing::warn!("Failed {}: {}", action, error);
+                ApiError::new(
+                    StatusCode::CONFLICT,
+                    "already_exists",
+                    format!("Failed {}: it already exists", action),
+                )
+            }
+            // foreign_key_violation
+            (_, Some("23503")) => {
+                tracing::warn!("Failed {}: {}", action, error);
+                ApiError::new(
+                    StatusCode::CONFLICT,
+                    "missing_reference",
+                    format!("Failed {}: something it refers to doesn't exist", action),
+                )
+            }
+            // check_violation, string_data_right_truncation and invalid_text_representation
+            (_, Some("23514" | "22001" | "22P02")) => {
+                tracing::warn!("Failed {}: {}", action, error);
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid",
+                    format!("Failed {}: a value isn't allowed", action),
+                )
+            }
+            (sqlx::Error::PoolTimedOut, _) => {
+                tracing::error!("Failed {}: {}", action, error);
+                ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "unavailable",
+                    "The server is too busy right now, try again shortly",
+                )
+            }
+            _ => ApiError::internal(action, error),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            code: self.code,
+            message: &self.message,
+            request_id: current_request_id(),
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
@@ -0,0 +1,294 @@
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Largest serialized state the server will accept, in bytes
+pub const MAX_STATE_BYTES: usize = 64 * 1024;
+
+const MAX_SUDOKU_HISTORY: usize = 1000;
+/// A month of play, far past any real solve time
+const MAX_SUDOKU_SECONDS: u32 = 30 * 24 * 60 * 60;
+const MAX_SQUAREWORD_GUESSES: usize = 200;
+const MAX_REPORTED_ERRORS: usize = 20;
+
+const SUDOKU_CELLS: usize = 81;
+const SQUAREWORD_WORD_LENGTH: usize = 5;
+
+/// Structured score columns the server derives from a saved game state
+#[derive(Default, Debug)]
+pub struct ScoreColumns {
+    pub elapsed_seconds: Option<i32>,
+    pub guess_count: Option<i32>,
+    pub mistakes: Option<i32>,
+    pub hints_used: Option<i32>,
+}
+
+#[derive(Debug)]
+pub struct StateError {
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Collects validation failures, keeping only the first few so a garbage state can't produce a
+/// huge response
+#[derive(Default)]
+struct Errors(Vec<StateError>);
+
+impl Errors {
+    fn push(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        if self.0.len() < MAX_REPORTED_ERRORS {
+            self.0.push(StateError {
+                field: field.into(),
+                message: message.into(),
+            });
+        }
+    }
+
+    fn into_result<T>(self, value: T) -> Result<T, Vec<StateError>> {
+        if self.0.is_empty() {
+            Ok(value)
+        } else {
+            Err(self.0)
+        }
+    }
+}
+
+fn parse<T: for<'de> Deserialize<'de>>(raw: &str) -> Result<T, Vec<StateError>> {
+    if raw.len() > MAX_STATE_BYTES {
+        return Err(vec![StateError {
+            field: "state".to_string(),
+            message: format!("must be at most {} bytes", MAX_STATE_BYTES),
+        }]);
+    }
+
+    serde_json::from_str(raw).map_err(|e| {
+        vec![StateError {
+            field: "state".to_string(),
+            message: e.to_string(),
+        }]
+    })
+}
+
+fn check_puzzle_id(errors: &mut Errors, id: Option<Uuid>, puzzle_id: Uuid) {
+    if id.is_some_and(|id| id != puzzle_id) {
+        errors.push("id", "does not match puzzle_id");
+    }
+}
+
+pub fn check_notes(notes: &[u8]) -> Option<&'static str> {
+    let mut seen = 0u16;
+    for &n in notes {
+        if !(1..=9).contains(&n) {
+            return Some("must be between 1 and 9");
+        }
+        if seen & (1 << n) != 0 {
+            return Some("must not repeat");
+        }
+        seen |= 1 << n;
+    }
+    None
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InputStyle {
+    Number,
+    Note,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SudokuCell {
+    pub value: Option<u8>,
+    pub is_given: bool,
+    pub check: Option<bool>,
+    pub notes: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SudokuSnapshot {
+    pub selected_cell: Option<u8>,
+    pub cells: Vec<SudokuCell>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SudokuState {
+    pub id: Option<Uuid>,
+    pub paused: bool,
+    pub seconds: u32,
+    pub input_style: InputStyle,
+    pub history: Option<Vec<SudokuSnapshot>>,
+    pub puzzle_day: Option<DateTime<Utc>>,
+}
+
+impl SudokuState {
+    /// Parses a client state and checks it against the puzzle it is being saved for. `puzzle` is
+    /// the 81 character puzzle string, with `-` for blank cells.
+    pub fn parse(raw: &str, puzzle_id: Uuid, puzzle: &str) -> Result<Self, Vec<StateError>> {
+        let state: SudokuState = parse(raw)?;
+        let mut errors = Errors::default();
+
+        check_puzzle_id(&mut errors, state.id, puzzle_id);
+
+        if state.seconds > MAX_SUDOKU_SECONDS {
+            errors.push("seconds", format!("must be at most {}", MAX_SUDOKU_SECONDS));
+        }
+
+        let history = state.history.as_deref().unwrap_or_default();
+        if history.len() > MAX_SUDOKU_HISTORY {
+            errors.push(
+                "history",
+                format!("must have at most {} entries", MAX_SUDOKU_HISTORY),
+            );
+        }
+
+        let givens: Vec<Option<u8>> = puzzle
+            .chars()
+            .map(|c| c.to_digit(10).map(|d| d as u8))
+            .collect();
+
+        for (h, snapshot) in history.iter().enumerate().take(MAX_SUDOKU_HISTORY) {
+            let field = format!("history[{}]", h);
+
+            if snapshot
+                .selected_cell
+                .is_some_and(|c| c as usize >= SUDOKU_CELLS)
+            {
+                errors.push(
+                    format!("{}.selectedCell", field),
+                    format!("must be less than {}", SUDOKU_CELLS),
+                );
+            }
+
+            if snapshot.cells.len() != SUDOKU_CELLS {
+                errors.push(
+                    format!("{}.cells", field),
+                    format!("must have exactly {} cells", SUDOKU_CELLS),
+                );
+                continue;
+            }
+
+            for (i, (cell, given)) in snapshot.cells.iter().zip(&givens).enumerate() {
+                let field = format!("{}.cells[{}]", field, i);
+
+                if cell.value.is_some_and(|v| !(1..=9).contains(&v)) {
+                    errors.push(format!("{}.value", field), "must be between 1 and 9");
+                }
+
+                if cell.is_given != given.is_some() {
+                    errors.push(
+                        format!("{}.isGiven", field),
+                        "does not match the puzzle's givens",
+                    );
+                } else if given.is_some() && cell.value != *given {
+                    errors.push(
+                        format!("{}.value", field),
+                        "does not match the puzzle's given value",
+                    );
+                }
+
+                if let Some(message) = check_notes(&cell.notes) {
+                    errors.push(format!("{}.notes", field), message);
+                }
+            }
+        }
+
+        errors.into_result(state)
+    }
+
+    pub fn score_columns(&self) -> ScoreColumns {
+        // Cells the player checked and got wrong in the most recent snapshot
+        let mistakes = self
+            .history
+            .as_ref()
+            .and_then(|history| history.last())
+            .map_or(0, |snapshot| {
+                snapshot
+                    .cells
+                    .iter()
+                    .filter(|c| c.check == Some(false))
+                    .count() as i32
+            });
+
+        ScoreColumns {
+            elapsed_seconds: i32::try_from(self.seconds).ok(),
+            guess_count: None,
+            mistakes: Some(mistakes),
+            hints_used: Some(0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SquarewordState {
+    pub id: Option<Uuid>,
+    pub guess: Vec<String>,
+    pub guess_history: Vec<String>,
+    pub puzzle_day: Option<DateTime<Utc>>,
+}
+
+impl SquarewordState {
+    /// Parses a client state, checking that guesses are well formed words
+    pub fn parse(raw: &str, puzzle_id: Uuid) -> Result<Self, Vec<StateError>> {
+        let state: SquarewordState = parse(raw)?;
+        let mut errors = Errors::default();
+
+        check_puzzle_id(&mut errors, state.id, puzzle_id);
+
+        if state.guess.len() > SQUAREWORD_WORD_LENGTH {
+            errors.push(
+                "guess",
+                format!("must have at most {} letters", SQUAREWORD_WORD_LENGTH),
+            );
+        }
+        for (i, letter) in state.guess.iter().enumerate() {
+            if letter.len() != 1 || !letter.chars().all(|c| c.is_ascii_alphabetic()) {
+                errors.push(format!("guess[{}]", i), "must be a single letter");
+            }
+        }
+
+        if state.guess_history.len() > MAX_SQUAREWORD_GUESSES {
+            errors.push(
+                "guessHistory",
+                format!("must have at most {} guesses", MAX_SQUAREWORD_GUESSES),
+            );
+        }
+        for (i, word) in state
+            .guess_history
+            .iter()
+            .enumerate()
+            .take(MAX_SQUAREWORD_GUESSES)
+        {
+            if word.len() != SQUAREWORD_WORD_LENGTH
+                || !word.chars().all(|c| c.is_ascii_alphabetic())
+            {
+                errors.push(
+                    format!("guessHistory[{}]", i),
+                    format!("must be a {} letter word", SQUAREWORD_WORD_LENGTH),
+                );
+            }
+        }
+
+        errors.into_result(state)
+    }
+
+    pub fn score_columns(&self) -> ScoreColumns {
+        ScoreColumns {
+            elapsed_seconds: None,
+            guess_count: Some(self.guess_history.len() as i32),
+            mistakes: None,
+            hints_used: Some(0),
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::time::Duration;
+
+use axum::{extract::State, Json};
+use chrono::Utc;
+use rand::Rng;
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    leaderboard,
+    session::{self, Tokens},
+    AppState, Game, User,
+};
+
+/// How often abandoned guests are looked for
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    ApiError::database("merging guest account", e)
+}
+
+/// Starts a session for a new guest, so people can play before signing in
+#[utoipa::path(
+    post,
+    path = "/guest",
+    tag = "auth",
+    responses(
+        (status = 200, description = "A session for a new guest", body = Tokens),
+    ),
+)]
+pub async fn create_guest(State(state): State<AppState>) -> Result<Json<Tokens>, ApiError> {
+    let name = format!("Guest {:04}", rand::thread_rng().gen_range(0..10000));
+
+    let user: User = sqlx::query_as(
+        "
+            insert into users (id, name, email, picture, created_at, last_login, provider, guest)
+            values ($1, $2, null, '', $3, $3, 'guest', true)
+            returning *
+        ",
+    )
+    .bind(Uuid::new_v4())
+    .bind(name)
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| ApiError::database("creating guest", e))?;
+
+    session::issue(&state, user).await.map(Json)
+}
+
+/// Moves everything a guest played into `user_id` and removes the guest. The guest proves who
+/// they are with their refresh token, which still works after their access token has expired. A
+/// token that isn't a live guest session is ignored, so a stale one never blocks signing in.
+pub async fn upgrade(
+    state: &AppState,
+    guest_refresh_token: &str,
+    user_id: Uuid,
+) -> Result<(), ApiError> {
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+    let merged = merge(&mut tx, guest_refresh_token, user_id).await?;
+    tx.commit().await.map_err(db_error)?;
+
+    for game in merged {
+        leaderboard::notify(state, game);
+    }
+
+    Ok(())
+}
+
+/// Does the work of `upgrade` inside `tx`, for callers that create the account in the same
+/// transaction. Returns the games whose boards changed, to notify once it's committed.
+pub async fn merge(
+    tx: &mut Transaction<'_, Postgres>,
+    guest_refresh_token: &str,
+    user_id: Uuid,
+) -> Result<Vec<Game>, ApiError> {
+    let guest_id = match session::redeem(&mut **tx, guest_refresh_token)
+        .await
+        .map_err(db_error)?
+    {
+        Some(id) if id != user_id => id,
+        _ => return Ok(Vec::new()),
+    };
+
+    let is_guest: bool = sqlx::query_scalar("select guest from users where id = $1")
+        .bind(guest_id)
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(db_error)?;
+    if !is_guest {
+        tracing::warn!(
+            "Refusing to merge {} into {}: not a guest",
+            guest_id,
+            user_id
+        );
+        return Ok(Vec::new());
+    }
+
+    let mut merged = Vec::new();
+    for game in [Game::Sudoku, Game::Squareword] {
+        if merge_scores(tx, game, guest_id, user_id).await? {
+            merged.push(game);
+        }
+    }
+
+    for sql in [
+        // Races both of them were in keep the account's own result
+        "update race_participants set user_id = $1 where user_id = $2 and race_id not in (select race_id from race_participants where user_id = $1)",
+        "delete from race_participants where user_id = $2",
+        "update races set created_by = $1 where created_by = $2",
+        "update races set winner_id = $1 where winner_id = $2",
+        "delete from moves where user_id = $2",
+        "delete from refresh_tokens where user_id = $2",
+        "delete from users where id = $2",
+    ] {
+        sqlx::query(sql)
+            .bind(user_id)
+            .bind(guest_id)
+            .execute(&mut **tx)
+            .await
+            .map_err(db_error)?;
+    }
+
+    Ok(merged)
+}
+
+/// Merges a guest's scores for `game` into the account's. Where both played a puzzle, the
+/// winning state is kept, or the most recent if neither or both won, and the moves recorded
+/// alongside it come with it. Returns whether any of the guest's states were kept.
+async fn merge_scores(
+    tx: &mut Transaction<'_, Postgres>,
+    game: Game,
+    guest_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool, ApiError> {
+    let table = game.scores_table();
+
+    let mut kept: Vec<Uuid> = sqlx::query_scalar(&format!(
+        "
+            update {table} u set
+                state = g.state,
+                winner = g.winner,
+                timestamp = g.timestamp,
+                late = g.late,
+                elapsed_seconds = g.elapsed_seconds,
+                guess_count = g.guess_count,
+                mistakes = g.mistakes,
+                hints_used = g.hints_used,
+                completed_at = g.completed_at,
+                version = u.version + 1
+            from {table} g
+            where u.user_id = $1 and g.user_id = $2 and g.puzzle_id = u.puzzle_id
+                and ((g.winner and not u.winner) or (g.winner = u.winner and g.timestamp > u.timestamp))
+            returning u.puzzle_id
+        ",
+    ))
+    .bind(user_id)
+    .bind(guest_id)
+    .fetch_all(&mut **tx)
+    .await
+    .map_err(db_error)?;
+
+    sqlx::query(&format!(
+        "delete from {table} g where g.user_id = $2 and exists (select 1 from {table} u where u.user_id = $1 and u.puzzle_id = g.puzzle_id)",
+    ))
+    .bind(user_id)
+    .bind(guest_id)
+    .execute(&mut **tx)
+    .await
+    .map_err(db_error)?;
+
+    let moved: Vec<Uuid> = sqlx::query_scalar(&format!(
+        "update {table} set user_id = $1 where user_id = $2 returning puzzle_id",
+    ))
+    .bind(user_id)
+    .bind(guest_id)
+    .fetch_all(&mut **tx)
+    .await
+    .map_err(db_error)?;
+    kept.extend(moved);
+
+    sqlx::query("delete from moves where user_id = $1 and game = $2 and puzzle_id = any($3)")
+        .bind(user_id)
+        .bind(game)
+        .bind(&kept)
+        .execute(&mut **tx)
+        .await
+        .map_err(db_error)?;
+
+    sqlx::query(
+        "update moves set user_id = $1 where user_id = $2 and game = $3 and puzzle_id = any($4)",
+    )
+    .bind(user_id)
+    .bind(guest_id)
+    .bind(game)
+    .bind(&kept)
+    .execute(&mut **tx)
+    .await
+    .map_err(db_error)?;
+
+    Ok(!kept.is_empty())
+}
+
+/// Deletes guests who can't come back: every refresh token they were given has expired or been
+/// revoked, and a guest has no other way to sign in. Returns how many were deleted.
+async fn expire(state: &AppState) -> Result<u64, sqlx::Error> {
+    let mut tx = state.pool.begin().await?;
+
+    let abandoned: Vec<Uuid> = sqlx::query_scalar(
+        "
+            select id from users u
+            where guest and created_at < now() - interval '1 day'
+                and not exists (
+                    select 1 from refresh_tokens t
+                    where t.user_id = u.id and t.revoked_at is null and t.expires_at > now()
+                )
+            for update skip locked
+        ",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if abandoned.is_empty() {
+        return Ok(0);
+    }
+
+    for sql in [
+        "delete from moves where user_id = any($1)",
+        "delete from sudoku_scores where user_id = any($1)",
+        "delete from squareword_scores where user_id = any($1)",
+        "delete from race_participants where user_id = any($1)",
+        "delete from refresh_tokens where user_id = any($1)",
+        "delete from user_identities where user_id = any($1)",
+        "delete from password_credentials where user_id = any($1)",
+        "delete from password_resets where user_id = any($1)",
+        "delete from users where id = any($1)",
+    ] {
+        sqlx::query(sql).bind(&abandoned).execute(&mut *tx).await?;
+    }
+
+    tx.commit().await?;
+
+    // Their wins are gone from the boards
+    leaderboard::notify(state, Game::Sudoku);
+    leaderboard::notify(state, Game::Squareword);
+
+    Ok(abandoned.len() as u64)
+}
+
+/// Clears out abandoned guests for as long as the server runs
+pub fn spawn_expiry(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            match expire(&state).await {
+                Ok(0) => {}
+                Ok(count) => tracing::info!("Deleted {} abandoned guests", count),
+                Err(e) => tracing::error!("Failed deleting abandoned guests: {}", e),
+            }
+            tokio::time::sleep(EXPIRE_INTERVAL).await;
+        }
+    });
+}
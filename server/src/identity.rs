@@ -0,0 +1,740 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{Path, State},
+    http::{header::CACHE_CONTROL, HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OnceCell, RwLock};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{config::Config, error::ApiError, AppState, User};
+
+pub const GOOGLE: &str = "google";
+
+/// Used when a provider's response doesn't say how long its keys can be cached
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// An unknown `kid` only triggers a refetch if the keys are at least this old, so tokens with
+/// made-up kids can't be used to hammer the provider
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Who a provider says signed in
+#[derive(Debug)]
+pub struct Identity {
+    pub subject: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub name: String,
+    pub picture: Option<String>,
+}
+
+#[async_trait]
+pub trait IdentityProvider: Send + Sync {
+    /// Checks a token from the provider's sign in flow, returning who it belongs to
+    async fn verify(&self, token: &str) -> Result<Identity, ApiError>;
+}
+
+/// An OpenID Connect provider, configured on the command line as `name,issuer,client_id`
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    name: String,
+    issuer: String,
+    client_id: String,
+}
+
+impl FromStr for OidcConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+        let [name, issuer, client_id] = parts[..] else {
+            return Err("expected a provider in the form name,issuer,client_id".to_string());
+        };
+        if name.is_empty() || issuer.is_empty() || client_id.is_empty() {
+            return Err("provider name, issuer and client id must not be empty".to_string());
+        }
+        // Plain http is allowed so a local mock provider can be used in testing
+        if !issuer.starts_with("https://") && !issuer.starts_with("http://") {
+            return Err(format!("issuer {} must be an http or https URL", issuer));
+        }
+
+        Ok(OidcConfig {
+            name: name.to_string(),
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id: client_id.to_string(),
+        })
+    }
+}
+
+impl OidcConfig {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kid: String,
+    kty: String,
+    #[serde(default)]
+    e: String,
+    #[serde(default)]
+    n: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    name: Option<String>,
+    preferred_username: Option<String>,
+    picture: Option<String>,
+}
+
+struct CachedKeys {
+    keys: HashMap<String, DecodingKey>,
+    fetched_at: Instant,
+    expires_at: Instant,
+}
+
+/// Verifies ID tokens from an OpenID Connect provider, finding its keys through discovery and
+/// caching them for as long as the provider allows
+pub struct Oidc {
+    issuer: String,
+    /// `iss` values accepted in tokens
+    issuers: Vec<String>,
+    client_id: String,
+    client: reqwest::Client,
+    jwks_uri: OnceCell<String>,
+    cache: RwLock<Option<CachedKeys>>,
+}
+
+/// Reads `max-age` out of a `Cache-Control` header
+fn max_age(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(CACHE_CONTROL)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        .and_then(|seconds| seconds.parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// Keeps why a token failed in the logs, since the details only help someone forging one
+fn invalid_token(e: jsonwebtoken::errors::Error) -> ApiError {
+    tracing::debug!("Rejected ID token: {:?}", e);
+    ApiError::unauthorized("Invalid token")
+}
+
+fn unknown_kid(kid: &str) -> ApiError {
+    ApiError::unauthorized(format!("Token is signed with an unknown key {}", kid))
+}
+
+impl Oidc {
+    pub fn new(config: &OidcConfig) -> Oidc {
+        Oidc {
+            issuer: config.issuer.clone(),
+            issuers: vec![config.issuer.clone()],
+            client_id: config.client_id.clone(),
+            client: reqwest::Client::new(),
+            jwks_uri: OnceCell::new(),
+            cache: RwLock::new(None),
+        }
+    }
+
+    /// Accepts tokens issued as `issuer` as well as the configured issuer
+    fn also_accepting(mut self, issuer: &str) -> Oidc {
+        self.issuers.push(issuer.to_string());
+        self
+    }
+
+    fn unavailable(&self, e: reqwest::Error) -> ApiError {
+        tracing::error!("Failed to query identity provider {}: {}", self.issuer, e);
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "provider_unavailable",
+            format!("Identity provider {} isn't reachable", self.issuer),
+        )
+    }
+
+    async fn jwks_uri(&self) -> Result<&String, ApiError> {
+        self.jwks_uri
+            .get_or_try_init(|| async {
+                let discovery = self
+                    .client
+                    .get(format!("{}/.well-known/openid-configuration", self.issuer))
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status())
+                    .map_err(|e| self.unavailable(e))?
+                    .json::<Discovery>()
+                    .await
+                    .map_err(|e| self.unavailable(e))?;
+
+                if discovery.issuer.trim_end_matches('/') != self.issuer {
+                    return Err(ApiError::new(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "provider_unavailable",
+                        format!(
+                            "Identity provider {} reports a different issuer {}",
+                            self.issuer, discovery.issuer
+                        ),
+                    ));
+                }
+
+                Ok(discovery.jwks_uri)
+            })
+            .await
+    }
+
+    async fn fetch(&self) -> Result<CachedKeys, ApiError> {
+        let res = self
+            .client
+            .get(self.jwks_uri().await?)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| self.unavailable(e))?;
+        let max_age = max_age(res.headers()).unwrap_or(DEFAULT_MAX_AGE);
+        let jwks = res.json::<Jwks>().await.map_err(|e| self.unavailable(e))?;
+
+        let keys = jwks
+            .keys
+            .iter()
+            .filter(|jwk| jwk.kty == "RSA")
+            .filter_map(
+                |jwk| match DecodingKey::from_rsa_components(&jwk.n, &jwk.e) {
+                    Ok(key) => Some((jwk.kid.clone(), key)),
+                    Err(e) => {
+                        tracing::warn!(
+                            "Skipping unusable key {} from {}: {}",
+                            jwk.kid,
+                            self.issuer,
+                            e
+                        );
+                        None
+                    }
+                },
+            )
+            .collect();
+
+        let now = Instant::now();
+        Ok(CachedKeys {
+            keys,
+            fetched_at: now,
+            expires_at: now + max_age,
+        })
+    }
+
+    /// Looks up the key for `kid`, refetching when the cache has expired or doesn't know the kid,
+    /// since that usually means the provider has rotated its keys
+    async fn key(&self, kid: &str) -> Result<DecodingKey, ApiError> {
+        {
+            let cache = self.cache.read().await;
+            if let Some(cached) = cache.as_ref() {
+                let fresh = cached.expires_at > Instant::now();
+                match cached.keys.get(kid) {
+                    Some(key) if fresh => return Ok(key.clone()),
+                    None if fresh && cached.fetched_at.elapsed() < MIN_REFETCH_INTERVAL => {
+                        return Err(unknown_kid(kid))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut cache = self.cache.write().await;
+        // Someone else may have refetched while we waited for the lock
+        let stale = match cache.as_ref() {
+            Some(cached) => {
+                cached.expires_at <= Instant::now()
+                    || (!cached.keys.contains_key(kid)
+                        && cached.fetched_at.elapsed() >= MIN_REFETCH_INTERVAL)
+            }
+            None => true,
+        };
+        if stale {
+            *cache = Some(self.fetch().await?);
+        }
+
+        cache
+            .as_ref()
+            .and_then(|cached| cached.keys.get(kid))
+            .cloned()
+            .ok_or_else(|| unknown_kid(kid))
+    }
+}
+
+#[async_trait]
+impl IdentityProvider for Oidc {
+    /// Checks an ID token's signature, expiry, audience and issuer
+    async fn verify(&self, token: &str) -> Result<Identity, ApiError> {
+        let header = jsonwebtoken::decode_header(token).map_err(invalid_token)?;
+        let kid = header
+            .kid
+            .ok_or_else(|| ApiError::unauthorized("Token header has no key id"))?;
+
+        let key = self.key(&kid).await?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_issuer(&self.issuers);
+
+        let claims = jsonwebtoken::decode::<IdTokenClaims>(token, &key, &validation)
+            .map_err(invalid_token)?
+            .claims;
+
+        let email = claims.email.ok_or_else(|| {
+            ApiError::unauthorized("The identity provider didn't share an email address")
+        })?;
+
+        Ok(Identity {
+            subject: claims.sub,
+            name: claims
+                .name
+                .or(claims.preferred_username)
+                .unwrap_or_else(|| email.clone()),
+            email,
+            email_verified: claims.email_verified.unwrap_or(false),
+            picture: claims.picture,
+        })
+    }
+}
+
+/// Every configured identity provider, by name
+pub struct Providers(HashMap<String, Box<dyn IdentityProvider>>);
+
+impl Providers {
+    pub fn new(config: &Config) -> Providers {
+        let google = OidcConfig {
+            name: GOOGLE.to_string(),
+            issuer: config.google_issuer.trim_end_matches('/').to_string(),
+            client_id: config.google_client_id.clone(),
+        };
+        // Google signs some ID tokens with an issuer that has no scheme
+        let google = Oidc::new(&google).also_accepting("accounts.google.com");
+
+        let mut providers: HashMap<String, Box<dyn IdentityProvider>> = HashMap::new();
+        providers.insert(GOOGLE.to_string(), Box::new(google));
+        for provider in &config.oidc_providers {
+            providers.insert(provider.name.clone(), Box::new(Oidc::new(provider)));
+        }
+
+        Providers(providers)
+    }
+
+    pub async fn verify(&self, provider: &str, token: &str) -> Result<Identity, ApiError> {
+        self.0
+            .get(provider)
+            .ok_or_else(|| {
+                ApiError::bad_request(format!("Unknown identity provider {}", provider))
+            })?
+            .verify(token)
+            .await
+    }
+}
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    ApiError::database("querying users", e)
+}
+
+/// Finds the user an identity belongs to, creating one if it is new. Accounts from before
+/// identities were recorded are matched by email, but only for the provider they signed up with,
+/// so another provider can't claim an account just by asserting the same email.
+pub async fn find_or_create_user(
+    state: &AppState,
+    provider: &str,
+    identity: Identity,
+) -> Result<User, ApiError> {
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+
+    let linked: Option<User> = sqlx::query_as(
+        "select u.* from user_identities i join users u on u.id = i.user_id where i.provider = $1 and i.subject = $2",
+    )
+    .bind(provider)
+    .bind(&identity.subject)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    let user = match linked {
+        Some(user) => user,
+        None => {
+            let existing: Option<(Uuid, String)> =
+                sqlx::query_as("select id, provider from users where lower(email) = lower($1)")
+                    .bind(&identity.email)
+                    .fetch_optional(&mut *tx)
+                    .await
+                    .map_err(db_error)?;
+
+            let user_id = match existing {
+                Some((id, signed_up_with)) if signed_up_with == provider && identity.email_verified => id,
+                Some(_) => {
+                    return Err(ApiError::conflict(format!(
+                            "An account already uses {}. Sign in to it and link {} from your profile instead.",
+                            identity.email, provider
+                        )))
+                }
+                None => {
+                    let id = Uuid::new_v4();
+                    sqlx::query("insert into users (id, name, email, picture, created_at, last_login, provider) values ($1, $2, $3, $4, $5, $6, $7)")
+                        .bind(id)
+                        .bind(&identity.name)
+                        .bind(&identity.email)
+                        .bind(identity.picture.clone().unwrap_or_default())
+                        .bind(Utc::now())
+                        .bind(Utc::now())
+                        .bind(provider)
+                        .execute(&mut *tx)
+                        .await
+                        .map_err(db_error)?;
+                    id
+                }
+            };
+
+            link(&mut tx, user_id, provider, &identity).await?;
+
+            sqlx::query_as("select * from users where id = $1")
+                .bind(user_id)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(db_error)?
+        }
+    };
+
+    sqlx::query("update users set last_login = $1 where id = $2")
+        .bind(Utc::now())
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+    tx.commit().await.map_err(db_error)?;
+
+    Ok(user)
+}
+
+async fn link(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user_id: Uuid,
+    provider: &str,
+    identity: &Identity,
+) -> Result<(), ApiError> {
+    let owner: Uuid = sqlx::query_scalar(
+        "
+            insert into user_identities (id, user_id, provider, subject, email, created_at) values ($1, $2, $3, $4, $5, $6)
+            on conflict on constraint user_identities_provider_subject_key do update set email = excluded.email
+            returning user_id
+        ",
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(provider)
+    .bind(&identity.subject)
+    .bind(&identity.email)
+    .bind(Utc::now())
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(db_error)?;
+
+    if owner != user_id {
+        return Err(ApiError::conflict(format!(
+            "That {} account is already linked to another user",
+            provider
+        )));
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, sqlx::FromRow, ToSchema)]
+pub struct LinkedIdentity {
+    provider: String,
+    email: String,
+    created_at: DateTime<Utc>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/me/identities",
+    tag = "account",
+    responses(
+        (status = 200, description = "Providers the user can sign in with", body = [LinkedIdentity]),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn identities(
+    user: User,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<LinkedIdentity>>, ApiError> {
+    let identities = sqlx::query_as(
+        "select provider, email, created_at from user_identities where user_id = $1 order by created_at",
+    )
+    .bind(user.id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    Ok(Json(identities))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct LinkRequest {
+    provider: String,
+    token: String,
+}
+
+/// Links another provider's account to the signed in user, so either can be used to sign in
+#[utoipa::path(
+    post,
+    path = "/me/identities",
+    tag = "account",
+    request_body = LinkRequest,
+    responses(
+        (status = 200, description = "The user's identities, including the new one", body = [LinkedIdentity]),
+        (status = 409, description = "That account is linked to someone else", body = ErrorBody),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn link_identity(
+    user: User,
+    State(state): State<AppState>,
+    Json(request): Json<LinkRequest>,
+) -> Result<Json<Vec<LinkedIdentity>>, ApiError> {
+    if user.guest {
+        return Err(ApiError::bad_request(
+            "Guests sign in to keep their scores instead of linking an account",
+        ));
+    }
+
+    let identity = state
+        .identity
+        .verify(&request.provider, &request.token)
+        .await?;
+
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+    link(&mut tx, user.id, &request.provider, &identity).await?;
+    tx.commit().await.map_err(db_error)?;
+
+    identities(user, State(state)).await
+}
+
+/// Unlinks a provider, as long as the user still has another way to sign in
+#[utoipa::path(
+    delete,
+    path = "/me/identities/{provider}",
+    tag = "account",
+    params(("provider" = String, Path, description = "The provider to unlink")),
+    responses(
+        (status = 200, description = "The identities left", body = [LinkedIdentity]),
+        (status = 409, description = "It's the only way left to sign in", body = ErrorBody),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn unlink_identity(
+    user: User,
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+) -> Result<Json<Vec<LinkedIdentity>>, ApiError> {
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+
+    let remaining: i64 = sqlx::query_scalar(
+        "
+            select
+                (select count(*) from user_identities where user_id = $1 and provider != $2)
+                + (select count(*) from password_credentials where user_id = $1)
+        ",
+    )
+    .bind(user.id)
+    .bind(&provider)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(db_error)?;
+    if remaining == 0 {
+        return Err(ApiError::conflict(
+            "You can't unlink your only way to sign in",
+        ));
+    }
+
+    sqlx::query("delete from user_identities where user_id = $1 and provider = $2")
+        .bind(user.id)
+        .bind(&provider)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+    tx.commit().await.map_err(db_error)?;
+
+    identities(user, State(state)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{response::IntoResponse, routing::get, Router};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{EncodingKey, Header};
+    use openssl::rsa::Rsa;
+    use serde_json::json;
+
+    use super::*;
+
+    const CLIENT_ID: &str = "gotd-test";
+    const KID: &str = "test-key";
+
+    /// Serves discovery and a JWKS with one RSA key on a local port, as a real provider would
+    async fn mock_provider() -> (String, EncodingKey) {
+        let rsa = Rsa::generate(2048).unwrap();
+        let jwk = json!({
+            "kid": KID,
+            "kty": "RSA",
+            "alg": "RS256",
+            "n": URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+            "e": URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+        });
+        let encoding = EncodingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let issuer = format!("http://{}", addr);
+
+        let discovery = json!({
+            "issuer": issuer,
+            "jwks_uri": format!("{}/jwks", issuer),
+        });
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(move || async move { Json(discovery) }),
+            )
+            .route(
+                "/jwks",
+                get(move || async move { Json(json!({ "keys": [jwk] })) }),
+            );
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        (issuer, encoding)
+    }
+
+    fn provider(issuer: &str) -> Oidc {
+        Oidc::new(&OidcConfig {
+            name: "mock".to_string(),
+            issuer: issuer.to_string(),
+            client_id: CLIENT_ID.to_string(),
+        })
+    }
+
+    fn token(key: &EncodingKey, kid: &str, claims: serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(kid.to_string());
+        jsonwebtoken::encode(&header, &claims, key).unwrap()
+    }
+
+    fn claims(issuer: &str, audience: &str, expires_in: i64) -> serde_json::Value {
+        json!({
+            "iss": issuer,
+            "aud": audience,
+            "sub": "subject-1",
+            "email": "player@example.com",
+            "email_verified": true,
+            "name": "Player One",
+            "exp": Utc::now().timestamp() + expires_in,
+        })
+    }
+
+    fn status(result: Result<Identity, ApiError>) -> StatusCode {
+        result.unwrap_err().into_response().status()
+    }
+
+    #[tokio::test]
+    async fn verifies_tokens_from_a_local_provider() {
+        let (issuer, key) = mock_provider().await;
+        let oidc = provider(&issuer);
+
+        let identity = oidc
+            .verify(&token(&key, KID, claims(&issuer, CLIENT_ID, 600)))
+            .await
+            .unwrap();
+        assert_eq!(identity.subject, "subject-1");
+        assert_eq!(identity.email, "player@example.com");
+        assert!(identity.email_verified);
+        assert_eq!(identity.name, "Player One");
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_tokens() {
+        let (issuer, key) = mock_provider().await;
+        let oidc = provider(&issuer);
+
+        let expired = token(&key, KID, claims(&issuer, CLIENT_ID, -3600));
+        assert_eq!(
+            status(oidc.verify(&expired).await),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_for_another_audience() {
+        let (issuer, key) = mock_provider().await;
+        let oidc = provider(&issuer);
+
+        let other = token(&key, KID, claims(&issuer, "someone-else", 600));
+        assert_eq!(status(oidc.verify(&other).await), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_from_another_issuer() {
+        let (issuer, key) = mock_provider().await;
+        let oidc = provider(&issuer);
+
+        let other = token(
+            &key,
+            KID,
+            claims("https://evil.example.com", CLIENT_ID, 600),
+        );
+        assert_eq!(status(oidc.verify(&other).await), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_signed_with_an_unknown_key() {
+        let (issuer, key) = mock_provider().await;
+        let oidc = provider(&issuer);
+
+        let unknown = token(&key, "rotated-away", claims(&issuer, CLIENT_ID, 600));
+        assert_eq!(
+            status(oidc.verify(&unknown).await),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+    Json,
+};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use futures_util::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{error::ApiError, today_in, AppState, Game, User};
+
+const DEFAULT_PER_PAGE: i64 = 25;
+const MAX_PER_PAGE: i64 = 100;
+const STREAM_CAPACITY: usize = 16;
+
+/// Carries the game of every winning save, so live leaderboards know when to refresh
+pub type Updates = broadcast::Sender<Game>;
+
+/// The boards someone is streaming, each carrying the board serialized once per change for every
+/// viewer
+pub type Streams = Arc<Mutex<HashMap<Board, broadcast::Sender<Arc<str>>>>>;
+
+/// Tells anyone streaming the leaderboard that a win has landed for `game`
+pub fn notify(state: &AppState, game: Game) {
+    // Only fails when nobody is streaming
+    let _ = state.leaderboard.send(game);
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    #[default]
+    Today,
+    Week,
+    Month,
+    All,
+}
+
+impl Period {
+    /// First puzzle day included in the period, `None` meaning no lower bound
+    fn start(self, today: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Period::Today => Some(today),
+            Period::Week => {
+                Some(today - Duration::days(today.weekday().num_days_from_monday() as i64))
+            }
+            Period::Month => today.with_day(1),
+            Period::All => None,
+        }
+    }
+
+    /// Solves needed to be ranked, so one quick solve can't top a board it's averaged over. Early
+    /// in a week or month it's capped at the puzzles there have been so far.
+    fn min_solves(self, today: NaiveDate) -> i64 {
+        let wanted = match self {
+            Period::Today => 1,
+            Period::Week => 3,
+            Period::Month => 7,
+            Period::All => 10,
+        };
+        match self.start(today) {
+            Some(start) => wanted.min((today - start).num_days() + 1),
+            None => wanted,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, IntoParams)]
+pub struct LeaderboardQuery {
+    game: Game,
+    #[serde(default)]
+    period: Period,
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+impl LeaderboardQuery {
+    fn board(&self) -> Board {
+        Board {
+            game: self.game,
+            period: self.period,
+            page: self.page.unwrap_or(1).max(1),
+            per_page: self
+                .per_page
+                .unwrap_or(DEFAULT_PER_PAGE)
+                .clamp(1, MAX_PER_PAGE),
+        }
+    }
+}
+
+/// One page of one board, with the query's defaults and limits applied
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Board {
+    game: Game,
+    period: Period,
+    page: i64,
+    per_page: i64,
+}
+
+#[derive(Serialize, sqlx::FromRow, ToSchema)]
+pub struct LeaderboardUser {
+    rank: i64,
+    user_id: Uuid,
+    name: String,
+    picture: Option<String>,
+    /// Whether `/users/{user_id}` can be viewed
+    public_profile: bool,
+    score: f64,
+    solves: i64,
+    finished_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LeaderboardResponse {
+    game: Game,
+    period: Period,
+    page: i64,
+    per_page: i64,
+    total: i64,
+    /// Solves in the period needed to be ranked
+    min_solves: i64,
+    users: Vec<LeaderboardUser>,
+    me: Option<LeaderboardUser>,
+}
+
+/// Sudoku is scored by solve time, squareword by guess count
+fn score_column(game: Game) -> &'static str {
+    match game {
+        Game::Sudoku => "s.elapsed_seconds",
+        Game::Squareword => "s.guess_count",
+    }
+}
+
+/// Builds the ranking shared by the page, own-rank and count queries. Only on-the-day wins count,
+/// lower average scores rank higher, and ties go to whoever finished first. Users with fewer than
+/// `$3` solves in the period, or who hide from the leaderboard, aren't ranked at all.
+fn ranked_query(game: Game) -> String {
+    format!(
+        "
+            with scores as (
+                select s.user_id, avg({score})::float8 as score, count(*) as solves, max(s.completed_at) as finished_at
+                from {scores_table} s
+                join {puzzles_table} p on p.id = s.puzzle_id
+                where s.winner and not s.late and {score} is not null and s.completed_at is not null
+                    and ($1::date is null or p.day >= $1) and p.day <= $2
+                group by s.user_id
+                having count(*) >= $3
+            ), ranked as (
+                select rank() over (order by scores.score, scores.finished_at) as rank,
+                    u.id as user_id, u.name, nullif(u.picture, '') as picture, u.public_profile,
+                    scores.score, scores.solves, scores.finished_at
+                from scores
+                join users u on u.id = scores.user_id
+                where not u.hide_from_leaderboard
+            )
+        ",
+        score = score_column(game),
+        scores_table = game.scores_table(),
+        puzzles_table = game.puzzles_table(),
+    )
+}
+
+async fn fetch(
+    state: &AppState,
+    user: Option<&User>,
+    query: Board,
+) -> Result<LeaderboardResponse, ApiError> {
+    let Board { page, per_page, .. } = query;
+
+    // Anonymous viewers see the board as of the server's default time zone
+    let today = match user {
+        Some(user) => user.today(state).await?,
+        None => today_in(state.config.default_timezone),
+    };
+    let start = query.period.start(today);
+    let min_solves = query.period.min_solves(today);
+    let offset = (page - 1)
+        .checked_mul(per_page)
+        .ok_or_else(|| ApiError::bad_request(format!("There is no page {}", page)))?;
+    let ranked = ranked_query(query.game);
+
+    let map_err = |e: sqlx::Error| ApiError::database("querying leaderboard", e);
+
+    let users: Vec<LeaderboardUser> = sqlx::query_as(&format!(
+        "{} select * from ranked order by rank, name limit $4 offset $5",
+        ranked
+    ))
+    .bind(start)
+    .bind(today)
+    .bind(min_solves)
+    .bind(per_page)
+    .bind(offset)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(map_err)?;
+
+    let total: i64 = sqlx::query_scalar(&format!("{} select count(*) from ranked", ranked))
+        .bind(start)
+        .bind(today)
+        .bind(min_solves)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(map_err)?;
+
+    let me: Option<LeaderboardUser> = match user {
+        Some(user) => sqlx::query_as(&format!(
+            "{} select * from ranked where user_id = $4",
+            ranked
+        ))
+        .bind(start)
+        .bind(today)
+        .bind(min_solves)
+        .bind(user.id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(map_err)?,
+        None => None,
+    };
+
+    Ok(LeaderboardResponse {
+        game: query.game,
+        period: query.period,
+        page,
+        per_page,
+        total,
+        min_solves,
+        users,
+        me,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/leaderboard",
+    tag = "leaderboard",
+    params(LeaderboardQuery),
+    responses(
+        (status = 200, description = "One page of the board, and where the signed in user ranks", body = LeaderboardResponse),
+        (status = 400, description = "The page is out of range", body = ErrorBody),
+    ),
+    security((), ("bearer" = []))
+)]
+pub async fn leaderboard(
+    user: Option<User>,
+    State(state): State<AppState>,
+    Query(query): Query<LeaderboardQuery>,
+) -> Result<Json<LeaderboardResponse>, ApiError> {
+    fetch(&state, user.as_ref(), query.board()).await.map(Json)
+}
+
+fn leaderboard_event(json: &str) -> Event {
+    Event::default().event("leaderboard").data(json)
+}
+
+/// Recomputes every board being streamed once per win, however many people are watching it, and
+/// sends it on if it changed
+pub fn spawn_streams(state: AppState) {
+    let mut wins = state.leaderboard.subscribe();
+    tokio::spawn(async move {
+        let mut last: HashMap<Board, String> = HashMap::new();
+        loop {
+            let game = match wins.recv().await {
+                Ok(game) => Some(game),
+                // Missed some wins, so every board is recomputed
+                Err(RecvError::Lagged(_)) => None,
+                Err(RecvError::Closed) => return,
+            };
+
+            let boards: Vec<Board> = {
+                let mut streams = state.leaderboard_streams.lock().unwrap();
+                streams.retain(|_, sender| sender.receiver_count() > 0);
+                last.retain(|board, _| streams.contains_key(board));
+                streams
+                    .keys()
+                    .filter(|board| game.is_none_or(|game| board.game == game))
+                    .copied()
+                    .collect()
+            };
+
+            for board in boards {
+                // A board that fails to load is skipped until the next win
+                let Ok(response) = fetch(&state, None, board).await else {
+                    continue;
+                };
+                let json = serde_json::to_string(&response).expect("leaderboards always serialize");
+                if last.get(&board) == Some(&json) {
+                    continue;
+                }
+
+                if let Some(sender) = state.leaderboard_streams.lock().unwrap().get(&board) {
+                    // Only fails when the last viewer has just left
+                    let _ = sender.send(Arc::from(json.as_str()));
+                }
+                last.insert(board, json);
+            }
+        }
+    });
+}
+
+/// Streams the leaderboard as server-sent events. The current board is sent straight away, then
+/// again whenever a win changes it.
+#[utoipa::path(
+    get,
+    path = "/leaderboard/stream",
+    tag = "leaderboard",
+    params(LeaderboardQuery),
+    responses(
+        (status = 200, description = "Server-sent events, each a `LeaderboardResponse` for the first page", content_type = "text/event-stream", body = LeaderboardResponse),
+    ),
+)]
+pub async fn leaderboard_stream(
+    State(state): State<AppState>,
+    Query(query): Query<LeaderboardQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let board = query.board();
+    let receiver = state
+        .leaderboard_streams
+        .lock()
+        .unwrap()
+        .entry(board)
+        .or_insert_with(|| broadcast::channel(STREAM_CAPACITY).0)
+        .subscribe();
+    let current = fetch(&state, None, board).await?;
+    let current: Arc<str> = serde_json::to_string(&current)
+        .expect("leaderboards always serialize")
+        .into();
+
+    let initial = leaderboard_event(&current);
+    let updates = stream::unfold((receiver, current), |(mut receiver, last)| async move {
+        loop {
+            match receiver.recv().await {
+                // A viewer who joined mid-refresh may already have this board
+                Ok(json) if json == last => continue,
+                Ok(json) => return Some((Ok(leaderboard_event(&json)), (receiver, json))),
+                // Each board replaces the last, so only the newest matters
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    let events = stream::once(async move { Ok(initial) }).chain(updates);
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
@@ -1,41 +1,111 @@
 // #![feature(test)]
 
+mod account;
+mod archive;
+mod config;
+mod coop;
+mod error;
+mod gamestate;
+mod guest;
+mod identity;
+mod leaderboard;
+mod moves;
+mod openapi;
+mod password;
+mod profile;
+mod race;
+mod ratelimit;
+mod scheduler;
+mod session;
+// The generators predate linting and are left as they were written
+#[allow(clippy::redundant_static_lifetimes)]
 mod squarewordgen;
+mod stats;
+#[allow(clippy::clone_on_copy, clippy::single_char_add_str)]
 mod sudokugen;
+mod telemetry;
+mod versioning;
 
 use axum::{
     async_trait,
-    extract::{FromRequestParts, State},
+    extract::{DefaultBodyLimit, FromRequestParts, Query, State},
     headers::{authorization::Bearer, Authorization},
     http::{request::Parts, StatusCode},
+    middleware,
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post},
     Json, Router, TypedHeader,
 };
-use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
 use clap::Parser;
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Validation};
+use error::ApiError;
+use gamestate::{ScoreColumns, SquarewordState, StateError, SudokuState};
 use serde::{Deserialize, Serialize};
+use session::Tokens;
 use sqlx::{
     postgres::{PgConnectOptions, PgPoolOptions},
     PgPool,
 };
-use tower_http::cors::CorsLayer;
+use tokio::sync::broadcast;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
 use uuid::Uuid;
 
-use std::{net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-const SECRET: &str = "secret";
+/// The date it is in `timezone`, which is the newest puzzle someone there can reach. Each date
+/// has one puzzle for everyone, it just starts earlier for some people than others.
+fn today_in(timezone: Tz) -> NaiveDate {
+    Utc::now().with_timezone(&timezone).date_naive()
+}
+
+/// Parses a stored time zone, falling back to the server's default
+fn zone_or_default(timezone: Option<&str>, config: &config::Config) -> Tz {
+    timezone
+        .and_then(|timezone| timezone.parse().ok())
+        .unwrap_or(config.default_timezone)
+}
+
+#[derive(Deserialize, Serialize, sqlx::Type, ToSchema, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+enum Game {
+    Sudoku,
+    Squareword,
+}
+
+impl Game {
+    fn name(self) -> &'static str {
+        match self {
+            Game::Sudoku => "sudoku",
+            Game::Squareword => "squareword",
+        }
+    }
+
+    fn puzzles_table(self) -> &'static str {
+        match self {
+            Game::Sudoku => "sudoku_puzzles",
+            Game::Squareword => "squareword_puzzles",
+        }
+    }
 
-fn midnight_today() -> NaiveDate {
-    let now = Utc::now()
-        .with_timezone(&chrono_tz::America::New_York)
-        .num_days_from_ce();
-    NaiveDate::from_num_days_from_ce_opt(now).unwrap()
+    fn scores_table(self) -> &'static str {
+        match self {
+            Game::Sudoku => "sudoku_scores",
+            Game::Squareword => "squareword_scores",
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+struct DayQuery {
+    /// Defaults to the user's today
+    day: Option<NaiveDate>,
 }
 
-#[derive(Serialize, sqlx::FromRow)]
+#[derive(Serialize, sqlx::FromRow, ToSchema)]
 struct SudokuGame {
     id: Uuid,
     puzzle: String,
@@ -44,108 +114,307 @@ struct SudokuGame {
     state: Option<String>,
     timestamp: Option<i64>,
     winner: Option<bool>,
+    late: Option<bool>,
+    version: Option<i64>,
 }
 
+/// Selects a sudoku puzzle along with the score of the user bound to `$1`
+const SELECT_SUDOKU_GAME: &str = "select p.id, p.puzzle, p.solution, p.day, s.state, s.timestamp, s.winner, s.late, s.version from sudoku_puzzles p left join sudoku_scores s on s.puzzle_id=p.id and s.user_id = $1";
+
+#[utoipa::path(
+    get,
+    path = "/sudoku/state",
+    tag = "sudoku",
+    params(DayQuery),
+    responses(
+        (status = 200, description = "The puzzle and the user's score on it", body = SudokuGame),
+        (status = 404, description = "No puzzle for that day yet", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
 async fn get_sudoku_state(
     user: User,
     State(state): State<AppState>,
-) -> Result<Json<SudokuGame>, (StatusCode, String)> {
+    Query(query): Query<DayQuery>,
+) -> Result<Json<SudokuGame>, ApiError> {
+    let today = user.today(&state).await?;
+    let day = query.day.unwrap_or(today);
+    if day > today {
+        return Err(ApiError::not_found(format!("No sudoku puzzle for {}", day)));
+    }
+
     let found_game: Option<SudokuGame> =
-        sqlx::query_as("select p.id, p.puzzle, p.solution, p.day, s.state, s.timestamp, s.winner from sudoku_puzzles p left join sudoku_scores s on s.puzzle_id=p.id and s.user_id = $1 where day = $2")
-            .bind(&user.id)
-            .bind(&midnight_today())
+        sqlx::query_as(&format!("{} where p.day = $2", SELECT_SUDOKU_GAME))
+            .bind(user.id)
+            .bind(day)
             .fetch_optional(&state.pool)
             .await
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed querying sudoku puzzle: {}", e.to_string()),
-                )
-            })?;
-
-    match found_game {
-        Some(found_game) => Ok(Json(found_game)),
-        None => {
-            let generated = if state.args.test_sudoku {
-                sudokugen::Sudoku {
-                puzzle: "4289751633761289459513642788197536242678415395342968-7-425873967836-945269543278-",
-                solution: "428975163376128945951364278819753624267841539534296817142587396783619452695432781",
-                difficulty: sudokugen::Difficulty::Medium,
-            }
-            } else {
-                sudokugen::generate(sudokugen::Difficulty::Medium)
-            };
+            .map_err(|e| ApiError::database("querying sudoku puzzle", e))?;
 
-            let new_id = Uuid::new_v4();
+    // Puzzles are generated ahead of time by the scheduler, so a missing one was never made
+    found_game
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("No sudoku puzzle for {}", day)))
+}
 
-            sqlx::query(
-                "insert into sudoku_puzzles (id, puzzle, solution, day) values ($1, $2, $3, $4)",
+fn invalid_state(game: &str, errors: Vec<StateError>) -> ApiError {
+    let details: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "invalid_state",
+        format!("Invalid {} state: {}", game, details.join("; ")),
+    )
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, ApiError> {
+    serde_json::to_string(value).map_err(|e| ApiError::internal("serializing state", e))
+}
+
+/// A checked game state, with what's needed to work out the moves it makes
+enum CheckedState<'a> {
+    Sudoku {
+        state: &'a SudokuState,
+        /// The puzzle's givens, which moves are worked out against
+        puzzle: &'a str,
+    },
+    Squareword(&'a SquarewordState),
+}
+
+impl CheckedState<'_> {
+    fn to_json(&self) -> Result<String, ApiError> {
+        match self {
+            CheckedState::Sudoku { state, .. } => to_json(state),
+            CheckedState::Squareword(state) => to_json(state),
+        }
+    }
+
+    fn score_columns(&self) -> ScoreColumns {
+        match self {
+            CheckedState::Sudoku { state, .. } => state.score_columns(),
+            CheckedState::Squareword(state) => state.score_columns(),
+        }
+    }
+}
+
+/// A checked save of one player's score
+struct ScoreSave<'a> {
+    game: Game,
+    user_id: Uuid,
+    puzzle_id: Uuid,
+    state: Option<CheckedState<'a>>,
+    timestamp: i64,
+    winner: bool,
+    late: bool,
+    /// The version being replaced, or `None` to replace whatever is there
+    version: Option<i64>,
+}
+
+/// Writes a score and logs the moves since the score it replaces, returning the new version.
+/// Nothing is written, and `None` comes back, if the score has moved past the expected version or
+/// is already won. Every save of either game goes through here, co-op rooms included.
+async fn store_score(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    save: ScoreSave<'_>,
+) -> Result<Option<i64>, ApiError> {
+    let table = save.game.scores_table();
+    let game_state = save.state.as_ref().map(|s| s.to_json()).transpose()?;
+    let columns = save
+        .state
+        .as_ref()
+        .map_or_else(ScoreColumns::default, |s| s.score_columns());
+
+    // The state being replaced, which new moves are worked out against
+    let previous: Option<String> = sqlx::query_scalar(&format!(
+        "select state from {} where user_id = $1 and puzzle_id = $2 for update",
+        table
+    ))
+    .bind(save.user_id)
+    .bind(save.puzzle_id)
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(|e| ApiError::database("querying score", e))?
+    .flatten();
+
+    let version: Option<i64> = sqlx::query_scalar(&format!(
+        "
+            insert into {table} (id, user_id, puzzle_id, state, timestamp, winner, late, elapsed_seconds, guess_count, mistakes, hints_used, completed_at, version)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, case when $6 then now() end, 1)
+            on conflict on constraint {table}_user_id_puzzle_id_key do update set state = $4, timestamp = $5, winner = $6, late = $7,
+                elapsed_seconds = $8, guess_count = $9, mistakes = $10, hints_used = $11, completed_at = case when $6 then now() end,
+                version = {table}.version + 1
+            where ($12::bigint is null or {table}.version = $12) and not {table}.winner
+            returning version
+        ",
+    ))
+    .bind(Uuid::new_v4())
+    .bind(save.user_id)
+    .bind(save.puzzle_id)
+    .bind(game_state)
+    .bind(save.timestamp)
+    .bind(save.winner)
+    .bind(save.late)
+    .bind(columns.elapsed_seconds)
+    .bind(columns.guess_count)
+    .bind(columns.mistakes)
+    .bind(columns.hints_used)
+    .bind(save.version)
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(|e| ApiError::database("saving score", e))?;
+
+    if version.is_none() {
+        return Ok(None);
+    }
+
+    match save.state {
+        Some(CheckedState::Sudoku { state, puzzle }) => {
+            let previous: Option<SudokuState> =
+                previous.and_then(|raw| serde_json::from_str(&raw).ok());
+            let moves = moves::sudoku_moves(previous.as_ref(), state, puzzle);
+            moves::record(
+                tx,
+                save.user_id,
+                save.game,
+                save.puzzle_id,
+                save.timestamp,
+                &moves,
             )
-            .bind(&new_id)
-            .bind(&generated.puzzle)
-            .bind(&generated.solution)
-            .bind(&midnight_today())
-            .execute(&state.pool)
-            .await
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed saving sudoku puzzle: {}", e.to_string()),
-                )
-            })?;
-
-            Ok(Json(SudokuGame {
-                id: new_id,
-                puzzle: generated.puzzle.to_string(),
-                solution: generated.solution.to_string(),
-                day: midnight_today(),
-                state: None,
-                timestamp: None,
-                winner: Some(false),
-            }))
+            .await?;
         }
+        Some(CheckedState::Squareword(state)) => {
+            let previous: Option<SquarewordState> =
+                previous.and_then(|raw| serde_json::from_str(&raw).ok());
+            let moves = moves::squareword_moves(previous.as_ref(), state);
+            moves::record(
+                tx,
+                save.user_id,
+                save.game,
+                save.puzzle_id,
+                save.timestamp,
+                &moves,
+            )
+            .await?;
+        }
+        None => {}
     }
+
+    Ok(version)
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct SaveSudokuStateRequest {
     puzzle_id: Uuid,
     state: Option<String>,
     timestamp: i64,
     winner: bool,
+    /// The last version of this score the client saw from the server, 0 if it has never seen one
+    version: i64,
 }
 
+#[derive(Serialize, ToSchema)]
+struct SaveStateResponse {
+    version: i64,
+}
+
+/// Saves a sudoku score. If the stored score has moved past the version the client last saw, or is
+/// already won, nothing is written and the current game comes back with a 409 instead.
+#[utoipa::path(
+    post,
+    path = "/sudoku/state",
+    tag = "sudoku",
+    request_body = SaveSudokuStateRequest,
+    responses(
+        (status = 200, description = "Saved", body = SaveStateResponse),
+        (status = 409, description = "The score changed since `version`", body = SudokuGame),
+        (status = 422, description = "The state isn't valid for this puzzle", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
 async fn save_sudoku_state(
     user: User,
     State(state): State<AppState>,
     Json(request): Json<SaveSudokuStateRequest>,
-) -> Result<(), (StatusCode, String)> {
-    sqlx::query(
-        "
-            insert into sudoku_scores (id, user_id, puzzle_id, state, timestamp, winner) values ($1, $2, $3, $4, $5, $6) 
-            on conflict on constraint sudoku_scores_user_id_puzzle_id_key do update set state = $4, timestamp = $5, winner = $6
-            where not exists (select 1 from sudoku_scores where user_id = $2 and puzzle_id = $3 and winner = true)
-        ",
-    )
-    .bind(&Uuid::new_v4())
-    .bind(&user.id)
-    .bind(&request.puzzle_id)
-    .bind(&request.state)
-    .bind(&request.timestamp)
-    .bind(&request.winner)
-    .execute(&state.pool)
-    .await
-    .map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed saving sudoku score: {}", e.to_string()),
-        )
+) -> Result<Response, ApiError> {
+    let puzzle: Option<(NaiveDate, String)> =
+        sqlx::query_as("select day, puzzle from sudoku_puzzles where id = $1")
+            .bind(request.puzzle_id)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(|e| ApiError::database("querying sudoku puzzle", e))?;
+    // A puzzle that exists for people further east isn't reachable until the user's day starts
+    let today = user.today(&state).await?;
+    let (day, puzzle) = puzzle.filter(|(day, _)| *day <= today).ok_or_else(|| {
+        ApiError::not_found(format!("No sudoku puzzle with id {}", request.puzzle_id))
     })?;
 
-    Ok(())
+    let parsed = match request.state.as_deref() {
+        Some(raw) => Some(
+            SudokuState::parse(raw, request.puzzle_id, &puzzle)
+                .map_err(|errors| invalid_state("sudoku", errors))?,
+        ),
+        None => None,
+    };
+
+    // Anything saved after the puzzle's day, in the user's time zone, is an archive solve
+    let late = day < today;
+
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|e| ApiError::database("starting transaction", e))?;
+
+    let version = store_score(
+        &mut tx,
+        ScoreSave {
+            game: Game::Sudoku,
+            user_id: user.id,
+            puzzle_id: request.puzzle_id,
+            state: parsed.as_ref().map(|state| CheckedState::Sudoku {
+                state,
+                puzzle: &puzzle,
+            }),
+            timestamp: request.timestamp,
+            winner: request.winner,
+            late,
+            version: Some(request.version),
+        },
+    )
+    .await?;
+
+    match version {
+        Some(version) => {
+            tx.commit()
+                .await
+                .map_err(|e| ApiError::database("saving sudoku score", e))?;
+
+            if request.winner {
+                telemetry::record_solve(Game::Sudoku, late);
+                if !late {
+                    leaderboard::notify(&state, Game::Sudoku);
+                }
+            }
+
+            Ok(Json(SaveStateResponse { version }).into_response())
+        }
+        None => {
+            // Nothing was written, so the transaction can just be dropped
+            drop(tx);
+
+            let current: SudokuGame =
+                sqlx::query_as(&format!("{} where p.id = $2", SELECT_SUDOKU_GAME))
+                    .bind(user.id)
+                    .bind(request.puzzle_id)
+                    .fetch_one(&state.pool)
+                    .await
+                    .map_err(|e| ApiError::database("querying sudoku puzzle", e))?;
+
+            Ok((StatusCode::CONFLICT, Json(current)).into_response())
+        }
+    }
 }
 
-#[derive(Serialize, sqlx::FromRow)]
+#[derive(Serialize, sqlx::FromRow, ToSchema)]
 struct SquarewordGame {
     id: Uuid,
     solution: String,
@@ -153,319 +422,364 @@ struct SquarewordGame {
     state: Option<String>,
     timestamp: Option<i64>,
     winner: Option<bool>,
+    late: Option<bool>,
+    version: Option<i64>,
 }
 
+/// Selects a squareword puzzle along with the score of the user bound to `$1`
+const SELECT_SQUAREWORD_GAME: &str = "select p.id, p.solution, p.day, s.state, s.timestamp, s.winner, s.late, s.version from squareword_puzzles p left join squareword_scores s on s.puzzle_id=p.id and s.user_id = $1";
+
+#[utoipa::path(
+    get,
+    path = "/squareword/state",
+    tag = "squareword",
+    params(DayQuery),
+    responses(
+        (status = 200, description = "The puzzle and the user's score on it", body = SquarewordGame),
+        (status = 404, description = "No puzzle for that day yet", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
 async fn get_squareword_state(
     user: User,
     State(state): State<AppState>,
-) -> Result<Json<SquarewordGame>, (StatusCode, String)> {
+    Query(query): Query<DayQuery>,
+) -> Result<Json<SquarewordGame>, ApiError> {
+    let today = user.today(&state).await?;
+    let day = query.day.unwrap_or(today);
+    if day > today {
+        return Err(ApiError::not_found(format!(
+            "No squareword puzzle for {}",
+            day
+        )));
+    }
+
     let found_game: Option<SquarewordGame> =
-        sqlx::query_as("select p.id, p.solution, p.day, s.state, s.timestamp, s.winner from squareword_puzzles p left join squareword_scores s on s.puzzle_id=p.id and s.user_id = $1 where p.day = $2")
-            .bind(&user.id)
-            .bind(&midnight_today())
+        sqlx::query_as(&format!("{} where p.day = $2", SELECT_SQUAREWORD_GAME))
+            .bind(user.id)
+            .bind(day)
             .fetch_optional(&state.pool)
             .await
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed querying squareword puzzle: {}", e.to_string()),
-                )
-            })?;
-
-    match found_game {
-        Some(found_game) => Ok(Json(found_game)),
-        None => {
-            let generated = squarewordgen::generate();
-
-            let new_id = Uuid::new_v4();
+            .map_err(|e| ApiError::database("querying squareword puzzle", e))?;
 
-            sqlx::query("insert into squareword_puzzles (id, solution, day) values ($1, $2, $3)")
-                .bind(&new_id)
-                .bind(generated)
-                .bind(&midnight_today())
-                .execute(&state.pool)
-                .await
-                .map_err(|e| {
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Failed generating new squareword puzzle: {}", e.to_string()),
-                    )
-                })?;
-
-            Ok(Json(SquarewordGame {
-                id: new_id,
-                solution: generated.to_string(),
-                day: midnight_today(),
-                state: None,
-                timestamp: None,
-                winner: Some(false),
-            }))
-        }
-    }
+    found_game
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("No squareword puzzle for {}", day)))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct SaveSquarewordScoreRequest {
     puzzle_id: Uuid,
     state: Option<String>,
     timestamp: i64,
     winner: bool,
+    version: i64,
 }
 
+/// Saves a squareword score, with the same version check as sudoku saves
+#[utoipa::path(
+    post,
+    path = "/squareword/state",
+    tag = "squareword",
+    request_body = SaveSquarewordScoreRequest,
+    responses(
+        (status = 200, description = "Saved", body = SaveStateResponse),
+        (status = 409, description = "The score changed since `version`", body = SquarewordGame),
+        (status = 422, description = "The state isn't valid for this puzzle", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
 async fn save_squareword_state(
     user: User,
     State(state): State<AppState>,
     Json(request): Json<SaveSquarewordScoreRequest>,
-) -> Result<(), (StatusCode, String)> {
-    sqlx::query(
-        "
-            insert into squareword_scores (id, user_id, puzzle_id, state, timestamp, winner) values ($1, $2, $3, $4, $5, $6) 
-            on conflict on constraint squareword_scores_user_id_puzzle_id_key do update set state = $4, timestamp=$5, winner=$6
-            where not exists (select 1 from squareword_scores where user_id = $2 and puzzle_id = $3 and winner = true)
-        ",
-    )
-    .bind(&Uuid::new_v4())
-    .bind(&user.id)
-    .bind(&request.puzzle_id)
-    .bind(&request.state)
-    .bind(&request.timestamp)
-    .bind(&request.winner)
-    .execute(&state.pool)
-    .await
-    .map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed saving sudoku score: {}", e.to_string()),
-        )
+) -> Result<Response, ApiError> {
+    let day: Option<NaiveDate> =
+        sqlx::query_scalar("select day from squareword_puzzles where id = $1")
+            .bind(request.puzzle_id)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(|e| ApiError::database("querying squareword puzzle", e))?;
+    let today = user.today(&state).await?;
+    let day = day.filter(|day| *day <= today).ok_or_else(|| {
+        ApiError::not_found(format!(
+            "No squareword puzzle with id {}",
+            request.puzzle_id
+        ))
     })?;
 
-    Ok(())
+    let parsed = match request.state.as_deref() {
+        Some(raw) => Some(
+            SquarewordState::parse(raw, request.puzzle_id)
+                .map_err(|errors| invalid_state("squareword", errors))?,
+        ),
+        None => None,
+    };
+
+    let late = day < today;
+
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|e| ApiError::database("starting transaction", e))?;
+
+    let version = store_score(
+        &mut tx,
+        ScoreSave {
+            game: Game::Squareword,
+            user_id: user.id,
+            puzzle_id: request.puzzle_id,
+            state: parsed.as_ref().map(CheckedState::Squareword),
+            timestamp: request.timestamp,
+            winner: request.winner,
+            late,
+            version: Some(request.version),
+        },
+    )
+    .await?;
+
+    match version {
+        Some(version) => {
+            tx.commit()
+                .await
+                .map_err(|e| ApiError::database("saving squareword score", e))?;
+
+            if request.winner {
+                telemetry::record_solve(Game::Squareword, late);
+                if !late {
+                    leaderboard::notify(&state, Game::Squareword);
+                }
+            }
+
+            Ok(Json(SaveStateResponse { version }).into_response())
+        }
+        None => {
+            // Nothing was written, so the transaction can just be dropped
+            drop(tx);
+
+            let current: SquarewordGame =
+                sqlx::query_as(&format!("{} where p.id = $2", SELECT_SQUAREWORD_GAME))
+                    .bind(user.id)
+                    .bind(request.puzzle_id)
+                    .fetch_one(&state.pool)
+                    .await
+                    .map_err(|e| ApiError::database("querying squareword puzzle", e))?;
+
+            Ok((StatusCode::CONFLICT, Json(current)).into_response())
+        }
+    }
 }
 
 #[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
 struct User {
     id: Uuid,
     name: String,
-    email: String,
+    email: Option<String>,
     picture: Option<String>,
     created_at: DateTime<Utc>,
     last_login: DateTime<Utc>,
+    #[serde(default)]
+    guest: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct GoogleJwt {
-    iss: String,
-    aud: String,
-    sub: String,
-    email: String,
-    email_verified: bool,
-    name: String,
-    picture: Option<String>,
-    given_name: String,
-    family_name: String,
-    iat: i64,
-    exp: i64,
-    jti: String,
-}
+impl User {
+    /// The user's time zone, read from the database rather than the session token so a change
+    /// applies straight away
+    async fn zone(&self, state: &AppState) -> Result<Tz, ApiError> {
+        let timezone: Option<String> =
+            sqlx::query_scalar("select timezone from users where id = $1")
+                .bind(self.id)
+                .fetch_optional(&state.pool)
+                .await
+                .map_err(|e| ApiError::database("querying time zone", e))?
+                .flatten();
+        Ok(zone_or_default(timezone.as_deref(), &state.config))
+    }
 
-#[derive(Deserialize)]
-struct GoogleCert {
-    kid: String,
-    e: String,
-    n: String,
-}
+    /// The newest puzzle day the user can reach
+    async fn today(&self, state: &AppState) -> Result<NaiveDate, ApiError> {
+        self.zone(state).await.map(today_in)
+    }
 
-#[derive(Deserialize)]
-struct GoogleCerts {
-    keys: Vec<GoogleCert>,
+    async fn is_admin(&self, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        let admin: Option<bool> = sqlx::query_scalar("select admin from users where id = $1")
+            .bind(self.id)
+            .fetch_optional(pool)
+            .await?;
+        Ok(admin.unwrap_or(false))
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct LoginRequest {
     token: String,
+    /// Which identity provider issued `token`, Google if not given
+    provider: Option<String>,
+    /// The refresh token of a guest session whose scores should move to this account
+    guest_refresh_token: Option<String>,
 }
 
+#[utoipa::path(
+    post,
+    path = "/login",
+    tag = "auth",
+    request_body = LoginRequest,
+    responses(
+        (status = 200, description = "Signed in", body = Tokens),
+        (status = 401, description = "The identity token isn't valid", body = ErrorBody),
+    )
+)]
 async fn login(
     State(state): State<AppState>,
     Json(req): Json<LoginRequest>,
-) -> Result<String, (StatusCode, String)> {
-    let header = jsonwebtoken::decode_header(&req.token).unwrap();
-    let kid = match header.kid {
-        Some(k) => k,
-        None => todo!(),
-    };
+) -> Result<Json<Tokens>, ApiError> {
+    let result = async {
+        let provider = req.provider.as_deref().unwrap_or(identity::GOOGLE);
+        let identity = state.identity.verify(provider, &req.token).await?;
 
-    let client = reqwest::Client::new();
-    let res = client
-        .get("https://www.googleapis.com/oauth2/v3/certs")
-        .send()
-        .await.map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, format!("Failed to query 'https://www.googleapis.com/oauth2/v3/certs' for certs: {:?}", e)))?
-        .json::<GoogleCerts>()
-        .await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to decode 'https://www.googleapis.com/oauth2/v3/certs' cert response: {:?}", e)))?;
-
-    let key = res.keys.iter().find(|k| k.kid == kid).unwrap();
-
-    let token = jsonwebtoken::decode::<GoogleJwt>(
-        &req.token,
-        &DecodingKey::from_rsa_components(&key.n, &key.e).unwrap(),
-        &Validation::new(Algorithm::RS256),
-    )
-    .map_err(|e| {
-        (
-            StatusCode::UNAUTHORIZED,
-            format!("Failed to decode token: {:?}", e),
-        )
-    })?;
+        let user = identity::find_or_create_user(&state, provider, identity).await?;
 
-    // Look for an existing user. If not, create one
-    let user: Option<User> = sqlx::query_as("select * from users where email = $1")
-        .bind(&token.claims.email)
-        .fetch_optional(&state.pool)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-
-    // Save the user if they don't exist, else create a new one
-    let user = match user {
-        Some(user) => {
-            // Update last login time
-            sqlx::query("update users set last_login = $1 where id = $2")
-                .bind(&Utc::now())
-                .bind(&user.id)
-                .execute(&state.pool)
-                .await
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-
-            user
-        }
-        None => {
-            let user = User {
-                id: Uuid::new_v4(),
-                name: token.claims.name.clone(),
-                email: token.claims.email.clone(),
-                picture: token.claims.picture.clone(),
-                created_at: Utc::now(),
-                last_login: Utc::now(),
-            };
-
-            sqlx::query("insert into users (id, name, email, picture, created_at, last_login) values ($1, $2, $3, $4, $5, $6)")
-                .bind(&user.id)
-                .bind(&user.name)
-                .bind(&user.email)
-                .bind(&(user.picture.clone().unwrap_or_else(|| "".to_string())))
-                .bind(&user.created_at)
-                .bind(&user.last_login)
-                .execute(&state.pool)
-                .await
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-
-            user
+        if let Some(guest_refresh_token) = &req.guest_refresh_token {
+            guest::upgrade(&state, guest_refresh_token, user.id).await?;
         }
-    };
 
-    let token = jsonwebtoken::encode(
-        &jsonwebtoken::Header::default(),
-        &user,
-        &EncodingKey::from_secret(SECRET.as_ref()),
-    )
-    .unwrap();
+        session::issue(&state, user).await
+    }
+    .await;
 
-    Ok(token)
+    telemetry::record_login("oidc", &result);
+    result.map(Json)
 }
 
 #[async_trait]
-impl<S> FromRequestParts<S> for User
-where
-    S: Send + Sync,
-{
-    type Rejection = Response;
+impl FromRequestParts<AppState> for User {
+    type Rejection = ApiError;
 
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
         let TypedHeader(Authorization(token)) =
             TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
                 .await
-                .map_err(|e| e.into_response())?;
-
-        let mut validation = Validation::new(Algorithm::HS256);
-        validation.validate_exp = false;
-        validation.required_spec_claims.clear();
-        let token_user = jsonwebtoken::decode::<User>(
-            &token.token(),
-            &DecodingKey::from_secret(SECRET.as_ref()),
-            &validation,
-        )
-        .map_err(|e| {
-            Response::builder()
-                .status(StatusCode::UNAUTHORIZED)
-                .body(format!("Failed to decode token: {:?}", e))
-                .unwrap()
-                .into_response()
-        })?;
-
-        Ok(token_user.claims)
+                .map_err(|_| ApiError::unauthorized("Sign in first"))?;
+
+        state.sessions.authenticate(token.token())
     }
 }
 
-async fn check_auth(user: User) -> Result<String, (StatusCode, String)> {
+async fn check_auth(user: User) -> Result<String, ApiError> {
     Ok(format!("{:?}", user))
 }
 
+#[utoipa::path(get, path = "/ping", tag = "meta", responses((status = 200, body = String)))]
 async fn pong() -> String {
-    return "pong\n".to_string();
-}
-
-#[derive(Serialize)]
-struct LeaderboardUser {
-    name: String,
-    picture: Option<String>,
-    sudoku_score: i32,
-    squareword_score: i32,
+    "pong\n".to_string()
 }
 
-#[derive(Serialize)]
-struct LeaderboardResponse {
-    users: Vec<LeaderboardUser>,
-}
-
-async fn leaderboard() -> Result<Json<LeaderboardResponse>, (StatusCode, String)> {
-    Ok(Json(LeaderboardResponse {
-        users: vec![
-            LeaderboardUser {
-                name: "Test User".to_string(),
-                picture: None,
-                sudoku_score: 150,
-                squareword_score: 300,
-            },
-            LeaderboardUser {
-                name: "Test User 2".to_string(),
-                picture: None,
-                sudoku_score: 200,
-                squareword_score: 85,
-            },
-        ],
-    }))
+#[derive(Clone)]
+struct AppState {
+    pool: PgPool,
+    config: config::Config,
+    rooms: coop::Rooms,
+    races: race::Channels,
+    leaderboard: leaderboard::Updates,
+    leaderboard_streams: leaderboard::Streams,
+    sessions: Arc<session::Sessions>,
+    identity: Arc<identity::Providers>,
+    limiter: Arc<ratelimit::Limiter>,
+    metrics: metrics_exporter_prometheus::PrometheusHandle,
 }
 
-#[derive(Parser, Debug, Clone)]
-struct Args {
-    #[arg(long, default_value = "localhost")]
-    db_host: String,
-
-    #[arg(long, default_value = "gotd")]
-    db_name: String,
-
-    #[arg(long, default_value = "false")]
-    test_sudoku: bool,
+/// The OpenAPI document at `{prefix}/openapi.json`, browsable at `{prefix}/docs`
+fn api_docs(prefix: &str) -> SwaggerUi {
+    SwaggerUi::new(format!("{}/docs", prefix)).url(
+        format!("{}/openapi.json", prefix),
+        openapi::ApiDoc::openapi(),
+    )
 }
 
-#[derive(Clone)]
-struct AppState {
-    pool: PgPool,
-    args: Args,
+/// Every route of the API. Mounted under `/api/v1`, and at the root for clients from before it
+/// was versioned.
+fn v1_routes(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/ping", get(pong))
+        .route("/sudoku/state", get(get_sudoku_state))
+        .route("/sudoku/state", post(save_sudoku_state))
+        .route("/squareword/state", get(get_squareword_state))
+        .route("/squareword/state", post(save_squareword_state))
+        .route("/sudoku/replay/:puzzle_id", get(moves::sudoku_replay))
+        .route("/sudoku/coop/:puzzle_id", get(coop::coop))
+        .route(
+            "/squareword/replay/:puzzle_id",
+            get(moves::squareword_replay),
+        )
+        .route("/race", post(race::create_race))
+        .route("/race/:code", get(race::get_race))
+        .route("/race/:code/join", post(race::join_race))
+        .route("/race/:code/start", post(race::start_race))
+        .route("/race/:code/progress", post(race::race_progress))
+        .route("/race/:code/guess", post(race::race_guess))
+        .route("/race/:code/finish", post(race::finish_race))
+        .route("/race/:code/live", get(race::live_race))
+        .route("/archive", get(archive::archive))
+        .route(
+            "/me",
+            get(profile::get_me)
+                .patch(profile::update_me)
+                .delete(account::delete_account),
+        )
+        .route("/me/export", get(account::export))
+        .route("/me/stats", get(stats::stats))
+        .route("/users/:user_id", get(profile::public_profile))
+        .route(
+            "/me/identities",
+            get(identity::identities).post(identity::link_identity),
+        )
+        .route(
+            "/me/identities/:provider",
+            delete(identity::unlink_identity),
+        )
+        .route("/login", post(login))
+        .route("/login/password", post(password::password_login))
+        .route("/register", post(password::register))
+        .route("/password/reset", post(password::reset_password))
+        .route("/admin/password_reset", post(password::create_reset))
+        .route("/me/password", post(password::change_password))
+        .route("/guest", post(guest::create_guest))
+        .route("/refresh", post(session::refresh))
+        .route("/logout", post(session::logout))
+        .route("/leaderboard", get(leaderboard::leaderboard))
+        .route("/leaderboard/stream", get(leaderboard::leaderboard_stream))
+        .route("/check_auth", get(check_auth))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            ratelimit::limit,
+        ))
+        .route_layer(middleware::from_fn(telemetry::track))
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    let args = config::Args::parse();
+    if let Some(config::Command::Openapi) = args.command {
+        println!(
+            "{}",
+            openapi::ApiDoc::openapi()
+                .to_pretty_json()
+                .expect("the OpenAPI document always serializes")
+        );
+        return;
+    }
+
+    let config = config::Config::load(args).unwrap_or_else(|errors| {
+        eprintln!("Invalid configuration:");
+        for error in errors {
+            eprintln!("  - {}", error);
+        }
+        std::process::exit(2);
+    });
 
     tracing_subscriber::registry()
         .with(
@@ -474,39 +788,78 @@ async fn main() {
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
+    let metrics = telemetry::install();
 
     let pool = PgPoolOptions::new()
-        .max_connections(5)
+        .max_connections(config.db_pool_size)
         .acquire_timeout(Duration::from_secs(30))
         .connect_with(
             PgConnectOptions::new()
-                .username("admin")
-                .password("pgpass")
-                .host(&args.db_host)
-                .port(5432)
-                .database(&args.db_name),
+                .username(&config.db_user)
+                .password(config.db_password.expose())
+                .host(&config.db_host)
+                .port(config.db_port)
+                .database(&config.db_name),
         )
         .await
         .expect("can't connect to database");
 
+    // Today's puzzles have to exist before anyone asks for them
+    scheduler::fill(&pool, &config).await;
+    scheduler::spawn(pool.clone(), config.clone());
+
+    let state = AppState {
+        pool,
+        sessions: Arc::new(session::Sessions::new(&config)),
+        identity: Arc::new(identity::Providers::new(&config)),
+        limiter: Arc::new(ratelimit::Limiter::new(&config)),
+        config: config.clone(),
+        rooms: coop::Rooms::default(),
+        races: race::Channels::default(),
+        leaderboard: broadcast::channel(64).0,
+        leaderboard_streams: leaderboard::Streams::default(),
+        metrics,
+    };
+    ratelimit::spawn_pruning(state.clone());
+    leaderboard::spawn_streams(state.clone());
+    guest::spawn_expiry(state.clone());
+
+    // Metrics are kept off the public listener, so they're only served on an address of their own
+    if let Some(addr) = config.metrics_listen {
+        let metrics_routes = Router::new()
+            .route("/metrics", get(telemetry::render))
+            .with_state(state.clone());
+        // Bound up front so a taken address stops the server rather than just the metrics
+        let metrics_server = axum::Server::bind(&addr).serve(metrics_routes.into_make_service());
+        tracing::debug!("serving metrics on {}", addr);
+        tokio::spawn(async move { metrics_server.await.unwrap() });
+        telemetry::spawn_daily(state.clone());
+    }
+
     let app = Router::new()
-        .route("/ping", get(pong))
-        .route("/sudoku/state", get(get_sudoku_state))
-        .route("/sudoku/state", post(save_sudoku_state))
-        .route("/squareword/state", get(get_squareword_state))
-        .route("/squareword/state", post(save_squareword_state))
-        .route("/login", post(login))
-        .route("/leaderboard", get(leaderboard))
-        .route("/check_auth", get(check_auth))
-        .layer(CorsLayer::permissive())
-        .with_state(AppState { pool, args });
+        .nest(versioning::V1, v1_routes(&state))
+        .merge(api_docs(versioning::V1))
+        .merge(
+            v1_routes(&state)
+                .merge(api_docs(""))
+                .layer(middleware::from_fn_with_state(
+                    versioning::Deprecation::legacy(&config),
+                    versioning::deprecated,
+                )),
+        )
+        // Leaves room for a maximum size state after JSON string escaping
+        .layer(DefaultBodyLimit::max(4 * gamestate::MAX_STATE_BYTES))
+        .layer(config.cors())
+        .layer(middleware::from_fn(error::request_id))
+        .with_state(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3001));
+    let addr = config.listen;
 
     tracing::debug!("listening on {}", addr);
 
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        // The peer address is who gets rate limited when there's no proxy in front
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
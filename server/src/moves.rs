@@ -0,0 +1,278 @@
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    gamestate::{SquarewordState, SudokuState},
+    AppState, Game, User,
+};
+
+/// A single cell edit. `value` and `notes` are what the cell holds after the move.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SudokuMove {
+    cell: u8,
+    value: Option<u8>,
+    notes: Vec<u8>,
+    seconds: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SquarewordMove {
+    guess: String,
+}
+
+/// Works out which cells changed between two saves. Clients only send their latest snapshot, so
+/// edits that were made and undone between saves don't show up.
+pub fn sudoku_moves(
+    previous: Option<&SudokuState>,
+    next: &SudokuState,
+    puzzle: &str,
+) -> Vec<SudokuMove> {
+    let Some(next_cells) = next.history.as_ref().and_then(|h| h.last()) else {
+        return Vec::new();
+    };
+    let previous_cells = previous
+        .and_then(|p| p.history.as_ref())
+        .and_then(|h| h.last());
+
+    next_cells
+        .cells
+        .iter()
+        .enumerate()
+        .filter(|(i, cell)| match previous_cells {
+            Some(previous) => !previous
+                .cells
+                .get(*i)
+                .is_some_and(|p| p.value == cell.value && p.notes == cell.notes),
+            // Against a fresh board, anything that isn't a given is a move
+            None => {
+                let given = puzzle.as_bytes().get(*i).is_some_and(|c| *c != b'-');
+                !given && (cell.value.is_some() || !cell.notes.is_empty())
+            }
+        })
+        .map(|(i, cell)| SudokuMove {
+            cell: i as u8,
+            value: cell.value,
+            notes: cell.notes.clone(),
+            seconds: next.seconds,
+        })
+        .collect()
+}
+
+pub fn squareword_moves(
+    previous: Option<&SquarewordState>,
+    next: &SquarewordState,
+) -> Vec<SquarewordMove> {
+    let seen = previous.map_or(0, |p| p.guess_history.len());
+
+    next.guess_history
+        .iter()
+        .skip(seen)
+        .map(|guess| SquarewordMove {
+            guess: guess.clone(),
+        })
+        .collect()
+}
+
+/// Appends moves to the log, numbering them after whatever is already recorded for the puzzle
+pub async fn record<T: Serialize>(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    game: Game,
+    puzzle_id: Uuid,
+    client_timestamp: i64,
+    moves: &[T],
+) -> Result<(), ApiError> {
+    if moves.is_empty() {
+        return Ok(());
+    }
+
+    let map_err = |e: sqlx::Error| ApiError::database("recording moves", e);
+
+    let last_seq: i32 = sqlx::query_scalar(
+        "select coalesce(max(seq), 0) from moves where user_id = $1 and game = $2 and puzzle_id = $3",
+    )
+    .bind(user_id)
+    .bind(game)
+    .bind(puzzle_id)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(map_err)?;
+
+    for (seq, m) in (last_seq + 1..).zip(moves) {
+        let serialized =
+            serde_json::to_string(m).map_err(|e| ApiError::internal("serializing move", e))?;
+
+        sqlx::query(
+            "insert into moves (id, user_id, game, puzzle_id, seq, move, client_timestamp, created_at) values ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(game)
+        .bind(puzzle_id)
+        .bind(seq)
+        .bind(serialized)
+        .bind(client_timestamp)
+        .bind(Utc::now())
+        .execute(&mut **tx)
+        .await
+        .map_err(map_err)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ReplayQuery {
+    /// Whose moves to replay, the signed in user's if not given
+    user_id: Option<Uuid>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReplayMove {
+    seq: i32,
+    #[serde(rename = "move")]
+    #[schema(value_type = Object)]
+    m: serde_json::Value,
+    client_timestamp: i64,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReplayResponse {
+    game: Game,
+    puzzle_id: Uuid,
+    user_id: Uuid,
+    moves: Vec<ReplayMove>,
+}
+
+#[derive(sqlx::FromRow)]
+struct MoveRow {
+    seq: i32,
+    #[sqlx(rename = "move")]
+    m: String,
+    client_timestamp: i64,
+    created_at: DateTime<Utc>,
+}
+
+async fn has_won(
+    state: &AppState,
+    game: Game,
+    user_id: Uuid,
+    puzzle_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let won: Option<bool> = sqlx::query_scalar(&format!(
+        "select winner from {} where user_id = $1 and puzzle_id = $2",
+        game.scores_table()
+    ))
+    .bind(user_id)
+    .bind(puzzle_id)
+    .fetch_optional(&state.pool)
+    .await?;
+
+    Ok(won.unwrap_or(false))
+}
+
+async fn replay(
+    user: User,
+    state: AppState,
+    game: Game,
+    puzzle_id: Uuid,
+    query: ReplayQuery,
+) -> Result<Json<ReplayResponse>, ApiError> {
+    let target = query.user_id.unwrap_or(user.id);
+
+    let map_err = |e: sqlx::Error| ApiError::database("querying replay", e);
+
+    // Other players' solves are only visible once both of you have finished, so a replay can't be
+    // used as a walkthrough. Admins can see anything.
+    if target != user.id && !user.is_admin(&state.pool).await.map_err(map_err)? {
+        let target_won = has_won(&state, game, target, puzzle_id)
+            .await
+            .map_err(map_err)?;
+        let user_won = has_won(&state, game, user.id, puzzle_id)
+            .await
+            .map_err(map_err)?;
+
+        if !(target_won && user_won) {
+            return Err(ApiError::forbidden("Replays of other players are only available once you have both finished the puzzle"));
+        }
+    }
+
+    let rows: Vec<MoveRow> = sqlx::query_as(
+        "select seq, move, client_timestamp, created_at from moves where user_id = $1 and game = $2 and puzzle_id = $3 order by seq",
+    )
+    .bind(target)
+    .bind(game)
+    .bind(puzzle_id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(map_err)?;
+
+    let moves = rows
+        .into_iter()
+        .map(|row| {
+            Ok(ReplayMove {
+                seq: row.seq,
+                m: serde_json::from_str(&row.m)
+                    .map_err(|e| ApiError::internal(&format!("decoding move {}", row.seq), e))?,
+                client_timestamp: row.client_timestamp,
+                created_at: row.created_at,
+            })
+        })
+        .collect::<Result<Vec<_>, ApiError>>()?;
+
+    Ok(Json(ReplayResponse {
+        game,
+        puzzle_id,
+        user_id: target,
+        moves,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/sudoku/replay/{puzzle_id}",
+    tag = "sudoku",
+    params(("puzzle_id" = Uuid, Path, description = "The puzzle's id"), ReplayQuery),
+    responses(
+        (status = 200, description = "Every recorded move, in order", body = ReplayResponse),
+        (status = 403, description = "Someone else's moves before solving the puzzle yourself", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn sudoku_replay(
+    user: User,
+    State(state): State<AppState>,
+    Path(puzzle_id): Path<Uuid>,
+    Query(query): Query<ReplayQuery>,
+) -> Result<Json<ReplayResponse>, ApiError> {
+    replay(user, state, Game::Sudoku, puzzle_id, query).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/squareword/replay/{puzzle_id}",
+    tag = "squareword",
+    params(("puzzle_id" = Uuid, Path, description = "The puzzle's id"), ReplayQuery),
+    responses(
+        (status = 200, description = "Every recorded move, in order", body = ReplayResponse),
+        (status = 403, description = "Someone else's moves before solving the puzzle yourself", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn squareword_replay(
+    user: User,
+    State(state): State<AppState>,
+    Path(puzzle_id): Path<Uuid>,
+    Query(query): Query<ReplayQuery>,
+) -> Result<Json<ReplayResponse>, ApiError> {
+    replay(user, state, Game::Squareword, puzzle_id, query).await
+}
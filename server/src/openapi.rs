@@ -0,0 +1,136 @@
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use crate::{
+    account, archive, error, guest, identity, leaderboard, moves, password, profile, race, session,
+    stats,
+};
+
+/// The document served at `/api/v1/openapi.json` and printed by `gotd openapi`
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "gotd", description = "Daily sudoku and squareword puzzles"),
+    servers((url = "/api/v1")),
+    paths(
+        crate::pong,
+        crate::login,
+        crate::get_sudoku_state,
+        crate::save_sudoku_state,
+        crate::get_squareword_state,
+        crate::save_squareword_state,
+        moves::sudoku_replay,
+        moves::squareword_replay,
+        crate::coop::coop,
+        race::create_race,
+        race::get_race,
+        race::join_race,
+        race::start_race,
+        race::race_progress,
+        race::race_guess,
+        race::finish_race,
+        race::live_race,
+        archive::archive,
+        profile::get_me,
+        profile::update_me,
+        profile::public_profile,
+        account::export,
+        account::delete_account,
+        stats::stats,
+        identity::identities,
+        identity::link_identity,
+        identity::unlink_identity,
+        password::register,
+        password::password_login,
+        password::change_password,
+        password::create_reset,
+        password::reset_password,
+        guest::create_guest,
+        session::refresh,
+        session::logout,
+        leaderboard::leaderboard,
+        leaderboard::leaderboard_stream,
+    ),
+    components(schemas(
+        error::ErrorBody,
+        crate::Game,
+        crate::SudokuGame,
+        crate::SquarewordGame,
+        crate::SaveSudokuStateRequest,
+        crate::SaveSquarewordScoreRequest,
+        crate::SaveStateResponse,
+        crate::LoginRequest,
+        session::Tokens,
+        session::RefreshRequest,
+        session::LogoutRequest,
+        moves::ReplayMove,
+        moves::ReplayResponse,
+        race::CreateRaceRequest,
+        race::ProgressRequest,
+        race::FinishRequest,
+        race::GuessRequest,
+        race::GuessResponse,
+        race::GuessRow,
+        race::RaceParticipant,
+        race::RacePuzzle,
+        race::RaceResponse,
+        archive::CompletionStatus,
+        archive::ArchiveGame,
+        archive::ArchiveDay,
+        archive::ArchiveResponse,
+        profile::Profile,
+        profile::ProfileUpdate,
+        profile::PublicProfile,
+        account::Profile,
+        account::ScoreExport,
+        account::MoveExport,
+        account::RaceExport,
+        account::Export,
+        stats::GameStats,
+        stats::SudokuStats,
+        stats::SquarewordStats,
+        stats::StatsResponse,
+        identity::LinkedIdentity,
+        identity::LinkRequest,
+        password::RegisterRequest,
+        password::PasswordLoginRequest,
+        password::ChangePasswordRequest,
+        password::CreateResetRequest,
+        password::PasswordReset,
+        password::ResetPasswordRequest,
+        leaderboard::Period,
+        leaderboard::LeaderboardUser,
+        leaderboard::LeaderboardResponse,
+    )),
+    modifiers(&BearerAuth),
+    tags(
+        (name = "sudoku"),
+        (name = "squareword"),
+        (name = "race", description = "Head to head races on a fresh puzzle"),
+        (name = "archive", description = "Past days' puzzles"),
+        (name = "leaderboard"),
+        (name = "account", description = "The signed in user's profile, settings and data"),
+        (name = "auth", description = "Signing in and sessions"),
+        (name = "meta"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Session access tokens go in an `Authorization: Bearer` header
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
@@ -0,0 +1,442 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{extract::State, Json};
+use chrono::{DateTime, Duration, Utc};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    guest, leaderboard, profile,
+    session::{self, Tokens},
+    telemetry, AppState, User,
+};
+
+/// What `users.provider` says for accounts that registered with a password
+const PROVIDER: &str = "password";
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Checked against when no account uses the email, so an unknown email takes as long to refuse as
+/// a wrong password. It's the hash of a random password nobody kept.
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$RIlX0Ctb3YtryikhGkZhLA$q2HnQCDyL6R6I5MoAyYKwRyVShUV3AicaHLLBZsNUGk";
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    ApiError::database("querying password credentials", e)
+}
+
+fn check_enabled(state: &AppState) -> Result<(), ApiError> {
+    if !state.config.password_accounts {
+        return Err(ApiError::not_found(
+            "Password accounts aren't enabled on this server",
+        ));
+    }
+    Ok(())
+}
+
+fn check_strength(password: &str) -> Result<(), ApiError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(ApiError::bad_request(format!(
+            "Passwords must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+// Hashing is deliberately slow, so it runs off the async workers
+async fn hash_password(password: String) -> Result<String, ApiError> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|hash| hash)
+    .map_err(|e| ApiError::internal("hashing password", e))
+}
+
+async fn verify_password(password: String, hash: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash)
+            .map(|parsed| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &parsed)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    })
+    .await
+    .unwrap_or(false)
+}
+
+async fn stored_hash(state: &AppState, user_id: Uuid) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("select password_hash from password_credentials where user_id = $1")
+        .bind(user_id)
+        .fetch_optional(&state.pool)
+        .await
+}
+
+async fn find_user(state: &AppState, user_id: Uuid) -> Result<User, ApiError> {
+    sqlx::query_as("select * from users where id = $1")
+        .bind(user_id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(db_error)
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RegisterRequest {
+    email: String,
+    name: String,
+    password: String,
+    guest_refresh_token: Option<String>,
+}
+
+/// Creates an account that signs in with an email address and password
+#[utoipa::path(
+    post,
+    path = "/register",
+    tag = "auth",
+    request_body = RegisterRequest,
+    responses(
+        (status = 200, description = "A session for the new account", body = Tokens),
+        (status = 409, description = "The email address is taken", body = ErrorBody),
+        (status = 404, description = "Password accounts aren't enabled", body = ErrorBody),
+    ),
+)]
+pub async fn register(
+    State(state): State<AppState>,
+    Json(request): Json<RegisterRequest>,
+) -> Result<Json<Tokens>, ApiError> {
+    check_enabled(&state)?;
+
+    let email = request.email.trim().to_lowercase();
+    if !email.contains('@') {
+        return Err(ApiError::bad_request("That isn't an email address"));
+    }
+    let name = profile::validate_name(&request.name)?;
+    check_strength(&request.password)?;
+
+    let password_hash = hash_password(request.password).await?;
+
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+
+    let taken: bool =
+        sqlx::query_scalar("select exists (select 1 from users where lower(email) = lower($1))")
+            .bind(&email)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_error)?;
+    if taken {
+        return Err(ApiError::conflict(format!(
+            "An account already uses {}",
+            email
+        )));
+    }
+
+    let user: User = sqlx::query_as(
+        "
+            insert into users (id, name, email, picture, created_at, last_login, provider)
+            values ($1, $2, $3, '', $4, $4, $5)
+            returning *
+        ",
+    )
+    .bind(Uuid::new_v4())
+    .bind(&name)
+    .bind(&email)
+    .bind(Utc::now())
+    .bind(PROVIDER)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    sqlx::query(
+        "insert into password_credentials (user_id, password_hash, created_at, updated_at) values ($1, $2, $3, $3)",
+    )
+    .bind(user.id)
+    .bind(password_hash)
+    .bind(Utc::now())
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    // Merged before committing, so a failed merge doesn't leave an account the client was told
+    // wasn't created
+    let merged = match &request.guest_refresh_token {
+        Some(guest_refresh_token) => guest::merge(&mut tx, guest_refresh_token, user.id).await?,
+        None => Vec::new(),
+    };
+
+    tx.commit().await.map_err(db_error)?;
+
+    for game in merged {
+        leaderboard::notify(&state, game);
+    }
+
+    session::issue(&state, user).await.map(Json)
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PasswordLoginRequest {
+    email: String,
+    password: String,
+    guest_refresh_token: Option<String>,
+}
+
+/// Signs in with an email address and password, issuing the same session as `login`
+#[utoipa::path(
+    post,
+    path = "/login/password",
+    tag = "auth",
+    request_body = PasswordLoginRequest,
+    responses(
+        (status = 200, description = "Signed in", body = Tokens),
+        (status = 401, description = "Wrong email or password", body = ErrorBody),
+        (status = 404, description = "Password accounts aren't enabled", body = ErrorBody),
+    ),
+)]
+pub async fn password_login(
+    State(state): State<AppState>,
+    Json(request): Json<PasswordLoginRequest>,
+) -> Result<Json<Tokens>, ApiError> {
+    check_enabled(&state)?;
+
+    let result = async {
+        let credential: Option<(Uuid, String)> = sqlx::query_as(
+            "select c.user_id, c.password_hash from password_credentials c join users u on u.id = c.user_id where lower(u.email) = lower($1)",
+        )
+        .bind(request.email.trim().to_lowercase())
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error)?;
+
+        // The same answer for unknown emails and wrong passwords, so this can't be used to find accounts
+        let verified = match credential {
+            Some((user_id, hash)) => verify_password(request.password, hash)
+                .await
+                .then_some(user_id),
+            None => {
+                verify_password(request.password, DUMMY_HASH.to_string()).await;
+                None
+            }
+        };
+        let user_id = verified.ok_or_else(|| ApiError::unauthorized("Incorrect email or password"))?;
+
+        sqlx::query("update users set last_login = $1 where id = $2")
+            .bind(Utc::now())
+            .bind(user_id)
+            .execute(&state.pool)
+            .await
+            .map_err(db_error)?;
+
+        if let Some(guest_refresh_token) = &request.guest_refresh_token {
+            guest::upgrade(&state, guest_refresh_token, user_id).await?;
+        }
+
+        let user = find_user(&state, user_id).await?;
+        session::issue(&state, user).await
+    }
+    .await;
+
+    telemetry::record_login("password", &result);
+    result.map(Json)
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+/// Changes the signed in user's password. Every other session is signed out, and the caller gets
+/// a fresh one.
+#[utoipa::path(
+    post,
+    path = "/me/password",
+    tag = "auth",
+    request_body = ChangePasswordRequest,
+    responses(
+        (status = 200, description = "A new session, with every other one signed out", body = Tokens),
+        (status = 401, description = "The current password is wrong", body = ErrorBody),
+        (status = 404, description = "Password accounts aren't enabled", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn change_password(
+    user: User,
+    State(state): State<AppState>,
+    Json(request): Json<ChangePasswordRequest>,
+) -> Result<Json<Tokens>, ApiError> {
+    check_enabled(&state)?;
+
+    let hash = stored_hash(&state, user.id).await.map_err(db_error)?;
+    let current_ok =
+        match hash {
+            Some(hash) => verify_password(request.current_password, hash).await,
+            None => return Err(ApiError::bad_request(
+                "You don't have a password to change. Ask an admin for a reset token to set one.",
+            )),
+        };
+    if !current_ok {
+        return Err(ApiError::unauthorized("Your current password is incorrect"));
+    }
+    check_strength(&request.new_password)?;
+
+    let password_hash = hash_password(request.new_password).await?;
+
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+    sqlx::query(
+        "update password_credentials set password_hash = $1, updated_at = $2 where user_id = $3",
+    )
+    .bind(password_hash)
+    .bind(Utc::now())
+    .bind(user.id)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+    session::revoke_all(&mut *tx, user.id)
+        .await
+        .map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+
+    session::issue(&state, user).await.map(Json)
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateResetRequest {
+    email: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PasswordReset {
+    reset_token: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Lets an admin hand someone a one-time token to set a new password. There's no email on an
+/// offline deployment, so the admin passes the token on themselves.
+#[utoipa::path(
+    post,
+    path = "/admin/password_reset",
+    tag = "auth",
+    request_body = CreateResetRequest,
+    responses(
+        (status = 200, description = "A one-time reset token", body = PasswordReset),
+        (status = 403, description = "Not an admin", body = ErrorBody),
+        (status = 404, description = "Password accounts aren't enabled", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn create_reset(
+    user: User,
+    State(state): State<AppState>,
+    Json(request): Json<CreateResetRequest>,
+) -> Result<Json<PasswordReset>, ApiError> {
+    check_enabled(&state)?;
+
+    if !user.is_admin(&state.pool).await.map_err(db_error)? {
+        return Err(ApiError::forbidden("Only admins can reset passwords"));
+    }
+
+    let target: Uuid = sqlx::query_scalar("select id from users where lower(email) = lower($1)")
+        .bind(request.email.trim().to_lowercase())
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| ApiError::not_found(format!("No account uses {}", request.email)))?;
+
+    let reset_token = session::random_token();
+    let expires_at = Utc::now() + Duration::hours(state.config.password_reset_hours);
+
+    sqlx::query(
+        "insert into password_resets (id, user_id, token_hash, created_by, created_at, expires_at) values ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(Uuid::new_v4())
+    .bind(target)
+    .bind(session::hash_token(&reset_token))
+    .bind(user.id)
+    .bind(Utc::now())
+    .bind(expires_at)
+    .execute(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    Ok(Json(PasswordReset {
+        reset_token,
+        expires_at,
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ResetPasswordRequest {
+    reset_token: String,
+    new_password: String,
+}
+
+/// Sets a new password with a reset token, signing out every existing session. Accounts that
+/// signed up with another provider get a password this way too.
+#[utoipa::path(
+    post,
+    path = "/password/reset",
+    tag = "auth",
+    request_body = ResetPasswordRequest,
+    responses(
+        (status = 200, description = "A session with the new password", body = Tokens),
+        (status = 400, description = "The reset token can't be used", body = ErrorBody),
+        (status = 404, description = "Password accounts aren't enabled", body = ErrorBody),
+    ),
+)]
+pub async fn reset_password(
+    State(state): State<AppState>,
+    Json(request): Json<ResetPasswordRequest>,
+) -> Result<Json<Tokens>, ApiError> {
+    check_enabled(&state)?;
+    check_strength(&request.new_password)?;
+
+    let password_hash = hash_password(request.new_password).await?;
+
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "
+            update password_resets set used_at = now()
+            where token_hash = $1 and used_at is null and expires_at > now()
+            returning user_id
+        ",
+    )
+    .bind(session::hash_token(&request.reset_token))
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(db_error)?
+    .ok_or_else(|| ApiError::bad_request("Reset token is invalid, expired or already used"))?;
+
+    sqlx::query(
+        "
+            insert into password_credentials (user_id, password_hash, created_at, updated_at) values ($1, $2, $3, $3)
+            on conflict (user_id) do update set password_hash = excluded.password_hash, updated_at = excluded.updated_at
+        ",
+    )
+    .bind(user_id)
+    .bind(password_hash)
+    .bind(Utc::now())
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    session::revoke_all(&mut *tx, user_id)
+        .await
+        .map_err(db_error)?;
+
+    tx.commit().await.map_err(db_error)?;
+
+    let user = find_user(&state, user_id).await?;
+    session::issue(&state, user).await.map(Json)
+}
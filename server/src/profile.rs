@@ -0,0 +1,305 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    stats::{self, StatsResponse},
+    today_in, zone_or_default, AppState, User,
+};
+
+const MAX_NAME_LENGTH: usize = 32;
+const MAX_PICTURE_LENGTH: usize = 2048;
+
+/// How long after changing time zone before it can be changed again. Each change moves when the
+/// user's puzzle day starts, so switching freely would open tomorrow's puzzle early or let a past
+/// day be solved on time.
+const TIMEZONE_COOLDOWN_DAYS: i64 = 7;
+
+/// Matched against whole words of a name, after undoing common letter substitutions
+const BLOCKED_WORDS: &[&str] = &[
+    "arse", "asshole", "bastard", "bitch", "bollocks", "cock", "cunt", "dick", "fag", "faggot",
+    "fuck", "nazi", "nigga", "nigger", "porn", "prick", "pussy", "rape", "retard", "shit", "slut",
+    "twat", "wank", "wanker", "whore",
+];
+
+/// Endings that still leave a blocked word offensive, like "fucking" or "shits"
+const BLOCKED_SUFFIXES: &[&str] = &["", "s", "es", "y", "ed", "er", "ers", "ing", "head", "face"];
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    ApiError::database("querying profile", e)
+}
+
+fn unleet(c: char) -> char {
+    match c {
+        '0' => 'o',
+        '1' | '!' | '|' => 'i',
+        '3' => 'e',
+        '4' | '@' => 'a',
+        '5' | '$' => 's',
+        '7' => 't',
+        c => c,
+    }
+}
+
+fn is_blocked(word: &str) -> bool {
+    BLOCKED_WORDS.iter().any(|blocked| {
+        word.strip_prefix(blocked)
+            .is_some_and(|rest| BLOCKED_SUFFIXES.contains(&rest))
+    })
+}
+
+/// Whether a name contains a blocked word, also catching ones spelled out with spaces or dots
+/// between the letters
+fn is_profane(name: &str) -> bool {
+    let normalized: String = name.to_lowercase().chars().map(unleet).collect();
+    let words: Vec<&str> = normalized
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .collect();
+
+    let spelled_out: String = words
+        .iter()
+        .filter(|w| w.chars().count() == 1)
+        .copied()
+        .collect();
+
+    words.iter().any(|w| is_blocked(w)) || is_blocked(&spelled_out)
+}
+
+pub(crate) fn validate_name(name: &str) -> Result<String, ApiError> {
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    if name.is_empty() {
+        return Err(ApiError::bad_request("Your name can't be empty"));
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ApiError::bad_request(format!(
+            "Names can be at most {} characters",
+            MAX_NAME_LENGTH
+        )));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(ApiError::bad_request(
+            "Names can't contain control characters",
+        ));
+    }
+    if is_profane(&name) {
+        return Err(ApiError::bad_request("Please pick a different name"));
+    }
+    Ok(name)
+}
+
+fn validate_timezone(timezone: &str) -> Result<String, ApiError> {
+    let timezone = timezone.trim();
+    if timezone.is_empty() {
+        return Ok(String::new());
+    }
+    timezone
+        .parse::<Tz>()
+        .map(|tz| tz.name().to_string())
+        .map_err(|_| ApiError::bad_request(format!("{} isn't a known time zone", timezone)))
+}
+
+/// An empty picture clears the avatar, anything else must be an https URL
+fn validate_picture(picture: &str) -> Result<String, ApiError> {
+    let picture = picture.trim();
+    if picture.is_empty() {
+        return Ok(String::new());
+    }
+    if picture.len() > MAX_PICTURE_LENGTH {
+        return Err(ApiError::bad_request("That picture URL is too long"));
+    }
+    match Url::parse(picture) {
+        Ok(url) if url.scheme() == "https" => Ok(picture.to_string()),
+        _ => Err(ApiError::bad_request("Pictures must be an https URL")),
+    }
+}
+
+#[derive(Serialize, sqlx::FromRow, ToSchema)]
+pub struct Profile {
+    id: Uuid,
+    name: String,
+    email: Option<String>,
+    picture: String,
+    guest: bool,
+    hide_from_leaderboard: bool,
+    public_profile: bool,
+    /// An IANA time zone name, or none to follow the server's default
+    timezone: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+async fn fetch(state: &AppState, user_id: Uuid) -> Result<Profile, ApiError> {
+    sqlx::query_as(
+        "select id, name, email, picture, guest, hide_from_leaderboard, public_profile, timezone, created_at from users where id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?
+    .ok_or_else(|| ApiError::not_found("No such user"))
+}
+
+#[utoipa::path(
+    get,
+    path = "/me",
+    tag = "account",
+    responses(
+        (status = 200, description = "The signed in user's profile and settings", body = Profile),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+        (status = 404, description = "The account has been deleted", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn get_me(user: User, State(state): State<AppState>) -> Result<Json<Profile>, ApiError> {
+    fetch(&state, user.id).await.map(Json)
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ProfileUpdate {
+    name: Option<String>,
+    picture: Option<String>,
+    hide_from_leaderboard: Option<bool>,
+    public_profile: Option<bool>,
+    /// An empty string goes back to the server's default. After the first change, the zone can
+    /// only be changed once a week.
+    timezone: Option<String>,
+}
+
+/// Updates whichever settings are given. Session tokens carry the name and picture, so clients
+/// refresh their session afterwards to pick up the change.
+#[utoipa::path(
+    patch,
+    path = "/me",
+    tag = "account",
+    request_body = ProfileUpdate,
+    responses(
+        (status = 200, description = "The updated profile", body = Profile),
+        (status = 400, description = "A setting isn't allowed", body = ErrorBody),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn update_me(
+    user: User,
+    State(state): State<AppState>,
+    Json(update): Json<ProfileUpdate>,
+) -> Result<Json<Profile>, ApiError> {
+    let name = update.name.as_deref().map(validate_name).transpose()?;
+    let picture = update
+        .picture
+        .as_deref()
+        .map(validate_picture)
+        .transpose()?;
+    let timezone = update
+        .timezone
+        .as_deref()
+        .map(validate_timezone)
+        .transpose()?;
+
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+
+    let mut timezone_changed_at = None;
+    if let Some(timezone) = &timezone {
+        let (current, changed_at): (Option<String>, Option<DateTime<Utc>>) = sqlx::query_as(
+            "select timezone, timezone_changed_at from users where id = $1 for update",
+        )
+        .bind(user.id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+        let wanted = Some(timezone.as_str()).filter(|timezone| !timezone.is_empty());
+        if current.as_deref() != wanted {
+            let now = Utc::now();
+            if let Some(changed_at) = changed_at {
+                let allowed_at = changed_at + Duration::days(TIMEZONE_COOLDOWN_DAYS);
+                if now < allowed_at {
+                    return Err(ApiError::bad_request(format!(
+                        "Your time zone can be changed again after {}",
+                        allowed_at.format("%Y-%m-%d %H:%M UTC")
+                    )));
+                }
+            }
+            timezone_changed_at = Some(now);
+        }
+    }
+
+    sqlx::query(
+        "
+            update users set
+                name = coalesce($1, name),
+                picture = coalesce($2, picture),
+                hide_from_leaderboard = coalesce($3, hide_from_leaderboard),
+                public_profile = coalesce($4, public_profile),
+                timezone = case when $5::text is null then timezone else nullif($5, '') end,
+                timezone_changed_at = coalesce($6, timezone_changed_at)
+            where id = $7
+        ",
+    )
+    .bind(name)
+    .bind(picture)
+    .bind(update.hide_from_leaderboard)
+    .bind(update.public_profile)
+    .bind(timezone)
+    .bind(timezone_changed_at)
+    .bind(user.id)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    tx.commit().await.map_err(db_error)?;
+
+    fetch(&state, user.id).await.map(Json)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PublicProfile {
+    id: Uuid,
+    name: String,
+    picture: Option<String>,
+    created_at: DateTime<Utc>,
+    stats: StatsResponse,
+}
+
+/// Someone's name, picture and stats, if they've opted in to a public profile
+#[utoipa::path(
+    get,
+    path = "/users/{user_id}",
+    tag = "account",
+    params(("user_id" = Uuid, Path, description = "Whose profile to show")),
+    responses(
+        (status = 200, description = "Their public profile", body = PublicProfile),
+        (status = 404, description = "No such user, or their profile is private", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn public_profile(
+    user: User,
+    State(state): State<AppState>,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<PublicProfile>, ApiError> {
+    let profile = fetch(&state, user_id).await?;
+
+    // A private profile looks the same as a missing one
+    if !profile.public_profile && user_id != user.id {
+        return Err(ApiError::not_found("No such user"));
+    }
+
+    let today = today_in(zone_or_default(profile.timezone.as_deref(), &state.config));
+
+    Ok(Json(PublicProfile {
+        id: profile.id,
+        name: profile.name,
+        picture: Some(profile.picture).filter(|p| !p.is_empty()),
+        created_at: profile.created_at,
+        stats: stats::for_user(&state, user_id, today).await?,
+    }))
+}
@@ -0,0 +1,849 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::StatusCode,
+    response::Response,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+use tokio::sync::broadcast::{self, error::RecvError};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{error::ApiError, squarewordgen, sudokugen, AppState, Game, User};
+
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 6;
+const MAX_PARTICIPANTS: i64 = 8;
+const BROADCAST_CAPACITY: usize = 64;
+const SQUAREWORD_SIZE: usize = 5;
+
+/// How long between a race being started and the puzzle being revealed, so every player gets it
+/// at the same moment
+fn countdown() -> Duration {
+    Duration::seconds(3)
+}
+
+/// Live event channels for races that someone is watching, keyed by race id
+pub type Channels = Arc<Mutex<HashMap<Uuid, broadcast::Sender<String>>>>;
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RaceEvent {
+    Joined { user_id: Uuid, name: String },
+    Started { started_at: DateTime<Utc> },
+    Progress { user_id: Uuid, progress: i32 },
+    Finished { user_id: Uuid, place: i32 },
+}
+
+fn publish(state: &AppState, race_id: Uuid, event: RaceEvent) {
+    if let Some(sender) = state.races.lock().unwrap().get(&race_id) {
+        let text = serde_json::to_string(&event).expect("race events always serialize");
+        // Only fails when nobody is watching
+        let _ = sender.send(text);
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct RaceRow {
+    id: Uuid,
+    code: String,
+    game: Game,
+    puzzle: Option<String>,
+    solution: Option<String>,
+    /// Cleared if the creator deletes their account
+    created_by: Option<Uuid>,
+    created_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+    winner_id: Option<Uuid>,
+}
+
+impl RaceRow {
+    /// Whether the countdown is over and the puzzle can be shown
+    fn started(&self) -> bool {
+        self.started_at.is_some_and(|s| s <= Utc::now())
+    }
+}
+
+#[derive(Serialize, sqlx::FromRow, ToSchema)]
+pub struct RaceParticipant {
+    user_id: Uuid,
+    name: String,
+    /// Cells filled for sudoku or guesses made for squareword, never the values themselves
+    progress: i32,
+    finished_at: Option<DateTime<Utc>>,
+    place: Option<i32>,
+    seconds: Option<f64>,
+}
+
+/// The puzzle, once the race has started. Solutions never leave the server: sudoku grids are
+/// checked on finishing, and squareword guesses one at a time.
+#[derive(Serialize, ToSchema)]
+pub struct RacePuzzle {
+    /// The sudoku's givens, or none for squareword, which starts from an empty grid
+    puzzle: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RaceResponse {
+    id: Uuid,
+    code: String,
+    game: Game,
+    created_by: Option<Uuid>,
+    created_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+    winner_id: Option<Uuid>,
+    participants: Vec<RaceParticipant>,
+    puzzle: Option<RacePuzzle>,
+}
+
+fn db_error(action: &str) -> impl Fn(sqlx::Error) -> ApiError + '_ {
+    move |e| ApiError::database(action, e)
+}
+
+fn generate_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LENGTH)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+async fn find_race(state: &AppState, code: &str) -> Result<RaceRow, ApiError> {
+    let race: Option<RaceRow> = sqlx::query_as("select * from races where code = $1")
+        .bind(code.to_ascii_uppercase())
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error("querying race"))?;
+
+    race.ok_or_else(|| ApiError::not_found(format!("No race with code {}", code)))
+}
+
+/// Locks a race for the rest of the transaction, so joins, starts and finishes happen one at a time
+async fn lock_race(tx: &mut Transaction<'_, Postgres>, code: &str) -> Result<RaceRow, ApiError> {
+    let race: Option<RaceRow> = sqlx::query_as("select * from races where code = $1 for update")
+        .bind(code.to_ascii_uppercase())
+        .fetch_optional(&mut **tx)
+        .await
+        .map_err(db_error("querying race"))?;
+
+    race.ok_or_else(|| ApiError::not_found(format!("No race with code {}", code)))
+}
+
+async fn race_response(state: &AppState, race: RaceRow) -> Result<Json<RaceResponse>, ApiError> {
+    let participants: Vec<RaceParticipant> = sqlx::query_as(
+        "
+            select rp.user_id, u.name, rp.progress, rp.finished_at, rp.place,
+                extract(epoch from (rp.finished_at - r.started_at))::float8 as seconds
+            from race_participants rp
+            join races r on r.id = rp.race_id
+            join users u on u.id = rp.user_id
+            where rp.race_id = $1
+            order by rp.place nulls last, rp.joined_at
+        ",
+    )
+    .bind(race.id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error("querying race participants"))?;
+
+    let puzzle = race.started().then_some(RacePuzzle {
+        puzzle: race.puzzle,
+    });
+
+    Ok(Json(RaceResponse {
+        id: race.id,
+        code: race.code,
+        game: race.game,
+        created_by: race.created_by,
+        created_at: race.created_at,
+        started_at: race.started_at,
+        finished_at: race.finished_at,
+        winner_id: race.winner_id,
+        participants,
+        puzzle,
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateRaceRequest {
+    game: Game,
+}
+
+/// Opens a race and returns its invite code. The puzzle isn't generated until the race starts.
+#[utoipa::path(
+    post,
+    path = "/race",
+    tag = "race",
+    request_body = CreateRaceRequest,
+    responses(
+        (status = 200, description = "The race", body = RaceResponse),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn create_race(
+    user: User,
+    State(state): State<AppState>,
+    Json(request): Json<CreateRaceRequest>,
+) -> Result<Json<RaceResponse>, ApiError> {
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(db_error("starting transaction"))?;
+
+    // Codes are short, so retry the rare collision rather than failing
+    let mut race = None;
+    for _ in 0..5 {
+        race = sqlx::query_as(
+            "
+                insert into races (id, code, game, created_by, created_at) values ($1, $2, $3, $4, $5)
+                on conflict on constraint races_code_key do nothing
+                returning *
+            ",
+        )
+        .bind(Uuid::new_v4())
+        .bind(generate_code())
+        .bind(request.game)
+        .bind(user.id)
+        .bind(Utc::now())
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error("creating race"))?;
+
+        if race.is_some() {
+            break;
+        }
+    }
+    let race: RaceRow = race.ok_or_else(|| {
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "unavailable",
+            "Failed finding a free race code",
+        )
+    })?;
+
+    sqlx::query("insert into race_participants (race_id, user_id, joined_at) values ($1, $2, $3)")
+        .bind(race.id)
+        .bind(user.id)
+        .bind(Utc::now())
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error("joining race"))?;
+
+    tx.commit().await.map_err(db_error("creating race"))?;
+
+    race_response(&state, race).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/race/{code}/join",
+    tag = "race",
+    params(("code" = String, Path, description = "The race's invite code")),
+    responses(
+        (status = 200, description = "The race", body = RaceResponse),
+        (status = 404, description = "No race with that code", body = ErrorBody),
+        (status = 409, description = "The race has started or is full", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn join_race(
+    user: User,
+    State(state): State<AppState>,
+    Path(code): Path<String>,
+) -> Result<Json<RaceResponse>, ApiError> {
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(db_error("starting transaction"))?;
+
+    let race = lock_race(&mut tx, &code).await?;
+    if race.started_at.is_some() {
+        return Err(ApiError::conflict("The race has already started"));
+    }
+
+    let count: i64 =
+        sqlx::query_scalar("select count(*) from race_participants where race_id = $1")
+            .bind(race.id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_error("querying race participants"))?;
+    if count >= MAX_PARTICIPANTS {
+        return Err(ApiError::conflict(format!(
+            "Races are limited to {} players",
+            MAX_PARTICIPANTS
+        )));
+    }
+
+    let joined = sqlx::query(
+        "insert into race_participants (race_id, user_id, joined_at) values ($1, $2, $3) on conflict do nothing",
+    )
+    .bind(race.id)
+    .bind(user.id)
+    .bind(Utc::now())
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error("joining race"))?;
+
+    tx.commit().await.map_err(db_error("joining race"))?;
+
+    if joined.rows_affected() > 0 {
+        publish(
+            &state,
+            race.id,
+            RaceEvent::Joined {
+                user_id: user.id,
+                name: user.name.clone(),
+            },
+        );
+    }
+
+    race_response(&state, race).await
+}
+
+/// Starts a race. Only its creator can start it, and only once someone else has joined.
+#[utoipa::path(
+    post,
+    path = "/race/{code}/start",
+    tag = "race",
+    params(("code" = String, Path, description = "The race's invite code")),
+    responses(
+        (status = 200, description = "The race", body = RaceResponse),
+        (status = 404, description = "No race with that code", body = ErrorBody),
+        (status = 403, description = "Only the creator can start a race, or any player once the creator's account is gone", body = ErrorBody),
+        (status = 409, description = "Already started, or nobody else has joined", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn start_race(
+    user: User,
+    State(state): State<AppState>,
+    Path(code): Path<String>,
+) -> Result<Json<RaceResponse>, ApiError> {
+    // Made before the race is locked, and thrown away if it can't start
+    let game = find_race(&state, &code).await?.game;
+    let (puzzle, solution) = tokio::task::spawn_blocking(move || match game {
+        Game::Sudoku => {
+            let generated = sudokugen::generate(sudokugen::Difficulty::Medium);
+            (Some(generated.puzzle), generated.solution)
+        }
+        Game::Squareword => (None, squarewordgen::generate().to_string()),
+    })
+    .await
+    .map_err(|e| ApiError::internal("generating race puzzle", e))?;
+
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(db_error("starting transaction"))?;
+
+    let race = lock_race(&mut tx, &code).await?;
+    if race.started_at.is_some() {
+        return Err(ApiError::conflict("The race has already started"));
+    }
+    match race.created_by {
+        Some(creator) if creator != user.id => {
+            return Err(ApiError::forbidden(
+                "Only the player who opened the race can start it",
+            ));
+        }
+        Some(_) => {}
+        // The creator deleted their account, so anyone left in the race can start it
+        None => {
+            let joined: bool = sqlx::query_scalar(
+                "select exists (select 1 from race_participants where race_id = $1 and user_id = $2)",
+            )
+            .bind(race.id)
+            .bind(user.id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_error("querying race participants"))?;
+            if !joined {
+                return Err(ApiError::forbidden("You aren't in this race"));
+            }
+        }
+    }
+
+    let count: i64 =
+        sqlx::query_scalar("select count(*) from race_participants where race_id = $1")
+            .bind(race.id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_error("querying race participants"))?;
+    if count < 2 {
+        return Err(ApiError::conflict("A race needs at least two players"));
+    }
+
+    let race: RaceRow = sqlx::query_as(
+        "update races set puzzle = $2, solution = $3, started_at = $4 where id = $1 returning *",
+    )
+    .bind(race.id)
+    .bind(puzzle)
+    .bind(solution)
+    .bind(Utc::now() + countdown())
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(db_error("starting race"))?;
+
+    tx.commit().await.map_err(db_error("starting race"))?;
+
+    if let Some(started_at) = race.started_at {
+        publish(&state, race.id, RaceEvent::Started { started_at });
+    }
+
+    race_response(&state, race).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/race/{code}",
+    tag = "race",
+    params(("code" = String, Path, description = "The race's invite code")),
+    responses(
+        (status = 200, description = "The race", body = RaceResponse),
+        (status = 404, description = "No race with that code", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn get_race(
+    _user: User,
+    State(state): State<AppState>,
+    Path(code): Path<String>,
+) -> Result<Json<RaceResponse>, ApiError> {
+    let race = find_race(&state, &code).await?;
+    race_response(&state, race).await
+}
+
+/// Checks that a race is under way and `user` is still racing in it
+async fn check_racing(
+    tx: &mut Transaction<'_, Postgres>,
+    race: &RaceRow,
+    user: &User,
+) -> Result<(), ApiError> {
+    if !race.started() {
+        return Err(ApiError::conflict("The race hasn't started yet"));
+    }
+
+    let finished: Option<Option<DateTime<Utc>>> = sqlx::query_scalar(
+        "select finished_at from race_participants where race_id = $1 and user_id = $2",
+    )
+    .bind(race.id)
+    .bind(user.id)
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(db_error("querying race participants"))?;
+
+    match finished {
+        None => Err(ApiError::forbidden("You aren't in this race")),
+        Some(Some(_)) => Err(ApiError::conflict("You have already finished this race")),
+        Some(None) => Ok(()),
+    }
+}
+
+fn max_progress(game: Game) -> i32 {
+    match game {
+        Game::Sudoku => 81,
+        Game::Squareword => 200,
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ProgressRequest {
+    progress: i32,
+}
+
+#[utoipa::path(
+    post,
+    path = "/race/{code}/progress",
+    tag = "race",
+    params(("code" = String, Path, description = "The race's invite code")),
+    request_body = ProgressRequest,
+    responses(
+        (status = 204, description = "Saved"),
+        (status = 404, description = "No race with that code", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn race_progress(
+    user: User,
+    State(state): State<AppState>,
+    Path(code): Path<String>,
+    Json(request): Json<ProgressRequest>,
+) -> Result<StatusCode, ApiError> {
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(db_error("starting transaction"))?;
+
+    let race = find_race(&state, &code).await?;
+    check_racing(&mut tx, &race, &user).await?;
+
+    let progress = request.progress.clamp(0, max_progress(race.game));
+    sqlx::query("update race_participants set progress = $3 where race_id = $1 and user_id = $2")
+        .bind(race.id)
+        .bind(user.id)
+        .bind(progress)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error("saving race progress"))?;
+
+    tx.commit()
+        .await
+        .map_err(db_error("saving race progress"))?;
+
+    publish(
+        &state,
+        race.id,
+        RaceEvent::Progress {
+            user_id: user.id,
+            progress,
+        },
+    );
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A finished sudoku grid. Squareword races finish on the guess that completes the grid instead.
+#[derive(Deserialize, ToSchema)]
+pub struct FinishRequest {
+    cells: String,
+}
+
+/// A squareword is solved once every letter of the grid has been guessed in its column
+fn squareword_solved(solution: &str, guesses: &[String]) -> bool {
+    let solution = solution.as_bytes();
+    let guesses: Vec<&[u8]> = guesses
+        .iter()
+        .map(|g| g.as_bytes())
+        .filter(|g| g.len() == SQUAREWORD_SIZE)
+        .collect();
+
+    solution.chunks(SQUAREWORD_SIZE).all(|word| {
+        word.iter()
+            .enumerate()
+            .all(|(col, letter)| guesses.iter().any(|g| g[col].eq_ignore_ascii_case(letter)))
+    })
+}
+
+/// Gives `user` the next place in the race, and the win if it's the first. The race must be
+/// locked, so places can't be handed out twice.
+async fn record_finish(
+    tx: &mut Transaction<'_, Postgres>,
+    race: RaceRow,
+    user: &User,
+    progress: i32,
+) -> Result<(RaceRow, i32), ApiError> {
+    let place: i32 = sqlx::query_scalar(
+        "
+            update race_participants set finished_at = now(), progress = $3,
+                place = (select count(*) from race_participants where race_id = $1 and finished_at is not null) + 1
+            where race_id = $1 and user_id = $2
+            returning place
+        ",
+    )
+    .bind(race.id)
+    .bind(user.id)
+    .bind(progress.min(max_progress(race.game)))
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(db_error("saving race result"))?;
+
+    let race: RaceRow = if place == 1 {
+        sqlx::query_as(
+            "update races set winner_id = $2, finished_at = now() where id = $1 returning *",
+        )
+        .bind(race.id)
+        .bind(user.id)
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(db_error("saving race result"))?
+    } else {
+        race
+    };
+
+    Ok((race, place))
+}
+
+fn wrong_game(game: Game) -> ApiError {
+    ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "wrong_game",
+        format!("That isn't how {} races are played", game.name()),
+    )
+}
+
+/// Records a sudoku finish. The grid is checked against the solution, and the first correct
+/// finish wins the race.
+#[utoipa::path(
+    post,
+    path = "/race/{code}/finish",
+    tag = "race",
+    params(("code" = String, Path, description = "The race's invite code")),
+    request_body = FinishRequest,
+    responses(
+        (status = 200, description = "The race", body = RaceResponse),
+        (status = 404, description = "No race with that code", body = ErrorBody),
+        (status = 422, description = "The answer isn't right", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn finish_race(
+    user: User,
+    State(state): State<AppState>,
+    Path(code): Path<String>,
+    Json(request): Json<FinishRequest>,
+) -> Result<Json<RaceResponse>, ApiError> {
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(db_error("starting transaction"))?;
+
+    let race = lock_race(&mut tx, &code).await?;
+    check_racing(&mut tx, &race, &user).await?;
+
+    if race.game != Game::Sudoku {
+        return Err(wrong_game(race.game));
+    }
+    if Some(request.cells.as_str()) != race.solution.as_deref() {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "incorrect_solution",
+            "That isn't the solution",
+        ));
+    }
+
+    let (race, place) = record_finish(&mut tx, race, &user, max_progress(Game::Sudoku)).await?;
+
+    tx.commit().await.map_err(db_error("saving race result"))?;
+
+    publish(
+        &state,
+        race.id,
+        RaceEvent::Finished {
+            user_id: user.id,
+            place,
+        },
+    );
+
+    race_response(&state, race).await
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct GuessRequest {
+    guess: String,
+}
+
+/// How a guess fits one word of the grid
+#[derive(Serialize, ToSchema)]
+pub struct GuessRow {
+    /// Whether each letter of the guess is the word's letter in that column
+    correct: Vec<bool>,
+    /// Letters of the guess that are in the word, but in a different column
+    misplaced: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct GuessResponse {
+    /// How the guess fits each word of the grid, top to bottom
+    rows: Vec<GuessRow>,
+    /// Set once the guesses so far have found every letter, which finishes the race
+    place: Option<i32>,
+}
+
+fn guess_row(word: &[u8], guess: &[u8]) -> GuessRow {
+    let correct: Vec<bool> = word.iter().zip(guess).map(|(w, g)| w == g).collect();
+    let mut misplaced: Vec<char> = guess
+        .iter()
+        .enumerate()
+        .filter(|(i, g)| !correct[*i] && word.iter().enumerate().any(|(j, w)| j != *i && w == *g))
+        .map(|(_, g)| *g as char)
+        .collect();
+    misplaced.sort_unstable();
+    misplaced.dedup();
+
+    GuessRow {
+        correct,
+        misplaced: misplaced.into_iter().collect(),
+    }
+}
+
+/// Makes a squareword guess. The server keeps the solution, so each guess is checked here and
+/// only how it fits the grid comes back. The guess that finds the last letter finishes the race.
+#[utoipa::path(
+    post,
+    path = "/race/{code}/guess",
+    tag = "race",
+    params(("code" = String, Path, description = "The race's invite code")),
+    request_body = GuessRequest,
+    responses(
+        (status = 200, description = "How the guess fits the grid", body = GuessResponse),
+        (status = 400, description = "The guess isn't a five letter word", body = ErrorBody),
+        (status = 404, description = "No race with that code", body = ErrorBody),
+        (status = 422, description = "The race isn't a squareword race", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn race_guess(
+    user: User,
+    State(state): State<AppState>,
+    Path(code): Path<String>,
+    Json(request): Json<GuessRequest>,
+) -> Result<Json<GuessResponse>, ApiError> {
+    let guess = request.guess.trim().to_ascii_lowercase();
+    if guess.len() != SQUAREWORD_SIZE || !guess.bytes().all(|b| b.is_ascii_lowercase()) {
+        return Err(ApiError::bad_request(format!(
+            "Guesses must be {} letter words",
+            SQUAREWORD_SIZE
+        )));
+    }
+
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(db_error("starting transaction"))?;
+
+    let race = lock_race(&mut tx, &code).await?;
+    if race.game != Game::Squareword {
+        return Err(wrong_game(race.game));
+    }
+    check_racing(&mut tx, &race, &user).await?;
+
+    let guesses: Vec<String> = sqlx::query_scalar(
+        "
+            update race_participants set guesses = array_append(guesses, $3),
+                progress = least(cardinality(guesses) + 1, $4)
+            where race_id = $1 and user_id = $2
+            returning guesses
+        ",
+    )
+    .bind(race.id)
+    .bind(user.id)
+    .bind(&guess)
+    .bind(max_progress(race.game))
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(db_error("saving race guess"))?;
+
+    let solution = race.solution.clone().unwrap_or_default();
+    let rows = solution
+        .as_bytes()
+        .chunks(SQUAREWORD_SIZE)
+        .map(|word| guess_row(word, guess.as_bytes()))
+        .collect();
+
+    let race_id = race.id;
+    let progress = guesses.len() as i32;
+    let place = if squareword_solved(&solution, &guesses) {
+        Some(record_finish(&mut tx, race, &user, progress).await?.1)
+    } else {
+        None
+    };
+
+    tx.commit().await.map_err(db_error("saving race guess"))?;
+
+    publish(
+        &state,
+        race_id,
+        match place {
+            Some(place) => RaceEvent::Finished {
+                user_id: user.id,
+                place,
+            },
+            None => RaceEvent::Progress {
+                user_id: user.id,
+                progress,
+            },
+        },
+    );
+
+    Ok(Json(GuessResponse { rows, place }))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct LiveQuery {
+    /// Browsers can't set headers on a WebSocket handshake, so the session token comes in the
+    /// query string instead
+    token: String,
+}
+
+async fn watch(mut socket: WebSocket, state: AppState, race_id: Uuid) {
+    let mut receiver = state
+        .races
+        .lock()
+        .unwrap()
+        .entry(race_id)
+        .or_insert_with(|| broadcast::channel(BROADCAST_CAPACITY).0)
+        .subscribe();
+
+    loop {
+        tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            event = receiver.recv() => {
+                let sent = match event {
+                    Ok(text) => socket.send(Message::Text(text)).await,
+                    // Progress is cumulative, so a watcher that fell behind only misses
+                    // intermediate counts
+                    Err(RecvError::Lagged(_)) => Ok(()),
+                    Err(RecvError::Closed) => break,
+                };
+                if sent.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    drop(receiver);
+    let mut channels = state.races.lock().unwrap();
+    if channels
+        .get(&race_id)
+        .is_some_and(|sender| sender.receiver_count() == 0)
+    {
+        channels.remove(&race_id);
+    }
+}
+
+/// Streams a race's joins, start, progress and finishes as they happen
+#[utoipa::path(
+    get,
+    path = "/race/{code}/live",
+    tag = "race",
+    params(("code" = String, Path, description = "The race's invite code"), LiveQuery),
+    responses(
+        (status = 101, description = "A WebSocket of race events as JSON text messages"),
+        (status = 404, description = "No race with that code", body = ErrorBody),
+    ),
+)]
+pub async fn live_race(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Path(code): Path<String>,
+    Query(query): Query<LiveQuery>,
+) -> Result<Response, ApiError> {
+    state.sessions.authenticate(&query.token)?;
+
+    let race = find_race(&state, &code).await?;
+
+    Ok(ws.on_upgrade(move |socket| watch(socket, state, race.id)))
+}
@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, State},
+    headers::{authorization::Bearer, Authorization, HeaderMapExt},
+    http::{header::RETRY_AFTER, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use uuid::Uuid;
+
+use crate::{config::Config, error::ApiError, versioning, AppState};
+
+/// How often buckets that have filled back up are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Routes that call out to an identity provider or hash a password, which are the expensive ones
+/// to repeat
+const AUTH_ROUTES: &[&str] = &[
+    "/login",
+    "/login/password",
+    "/register",
+    "/password/reset",
+    "/admin/password_reset",
+    "/me/password",
+    "/me/identities",
+    "/guest",
+    "/refresh",
+];
+
+/// Requests allowed in a window, as `count/unit` like `10/min`. Bursts of up to `count` go
+/// through at once, and the allowance refills evenly over the window.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    count: u32,
+    per: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, unit) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected a rate like 10/min, not {}", s))?;
+        let count: u32 = count
+            .trim()
+            .parse()
+            .map_err(|_| format!("{} isn't a number of requests", count))?;
+        if count == 0 {
+            return Err("the number of requests must be positive".to_string());
+        }
+        let per = match unit.trim() {
+            "s" | "sec" | "second" => Duration::from_secs(1),
+            "m" | "min" | "minute" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(60 * 60),
+            unit => return Err(format!("unknown unit {}, expected s, min or hour", unit)),
+        };
+        Ok(RateLimit { count, per })
+    }
+}
+
+/// An address or CIDR block, like `10.0.0.0/8`
+#[derive(Clone, Copy, Debug)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("{} isn't an IP address", addr))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("{} isn't a prefix length for {}", prefix, addr))?,
+            None => max,
+        };
+        Ok(IpRange { addr, prefix })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Group {
+    Auth,
+    Write,
+    Read,
+}
+
+impl Group {
+    fn of(method: &Method, path: &str) -> Group {
+        // Routes are mounted both at the root and under the current version
+        let path = path.strip_prefix(versioning::V1).unwrap_or(path);
+        if *method != Method::GET && AUTH_ROUTES.contains(&path) {
+            Group::Auth
+        } else if *method == Method::GET || *method == Method::HEAD {
+            Group::Read
+        } else {
+            Group::Write
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Client {
+    User(Uuid),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Client::User(id) => write!(f, "user {}", id),
+            Client::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets for every client and route group, shared by every copy of the routes
+pub struct Limiter {
+    auth: RateLimit,
+    write: RateLimit,
+    read: RateLimit,
+    trusted_proxies: Vec<IpRange>,
+    buckets: Mutex<HashMap<(Group, Client), Bucket>>,
+}
+
+impl Limiter {
+    pub fn new(config: &Config) -> Limiter {
+        Limiter {
+            auth: config.rate_limit_auth,
+            write: config.rate_limit_write,
+            read: config.rate_limit_read,
+            trusted_proxies: config.trusted_proxies.clone(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn limit(&self, group: Group) -> RateLimit {
+        match group {
+            Group::Auth => self.auth,
+            Group::Write => self.write,
+            Group::Read => self.read,
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|range| range.contains(ip))
+    }
+
+    /// The address the request came from. Behind trusted proxies, that's the last address in
+    /// `X-Forwarded-For` that isn't one of them, since anything before it could be made up by the
+    /// client.
+    fn client_ip<B>(&self, request: &Request<B>) -> Option<IpAddr> {
+        let peer = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())?;
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+
+        let forwarded: Vec<IpAddr> = request
+            .headers()
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| ip.trim().parse().ok())
+            .collect();
+
+        forwarded
+            .iter()
+            .rev()
+            .find(|ip| !self.is_trusted(**ip))
+            .or(forwarded.first())
+            .copied()
+            .or(Some(peer))
+    }
+
+    /// Takes a token from the client's bucket, or says how long until there is one
+    fn take(&self, group: Group, client: Client) -> Result<(), Duration> {
+        let limit = self.limit(group);
+        let capacity = limit.count as f64;
+        let per_second = capacity / limit.per.as_secs_f64();
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry((group, client)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+
+    /// Drops buckets that have refilled, which behave the same as a new one
+    fn prune(&self) {
+        let now = Instant::now();
+        self.buckets.lock().unwrap().retain(|(group, _), bucket| {
+            let limit = self.limit(*group);
+            now.duration_since(bucket.updated) < limit.per
+        });
+    }
+}
+
+/// Forgets idle clients for as long as the server runs
+pub fn spawn_pruning(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(PRUNE_INTERVAL).await;
+            state.limiter.prune();
+        }
+    });
+}
+
+/// Limits each client to its share of requests for the route group. Signed in users are counted
+/// by account, so people sharing an office network don't run into each other, and everyone else
+/// by address.
+pub async fn limit<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| request.uri().path(), |p| p.as_str());
+    let group = Group::of(request.method(), path);
+
+    let user = request
+        .headers()
+        .typed_get::<Authorization<Bearer>>()
+        .and_then(|Authorization(token)| state.sessions.verify(token.token()).ok());
+    let client = match (user, state.limiter.client_ip(&request)) {
+        (Some(user), _) => Client::User(user.id),
+        (None, Some(ip)) => Client::Ip(ip),
+        // Only without connection info, which the server always records
+        (None, None) => return next.run(request).await,
+    };
+
+    match state.limiter.take(group, client) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            tracing::warn!("Rate limited {} on {:?} routes", client, group);
+            // Rounded up, so retrying right on time finds a token
+            let retry_after = wait.as_secs() + 1;
+            (
+                [(RETRY_AFTER, retry_after.to_string())],
+                ApiError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate_limited",
+                    format!("Too many requests, try again in {} seconds", retry_after),
+                ),
+            )
+                .into_response()
+        }
+    }
+}
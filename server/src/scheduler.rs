@@ -0,0 +1,137 @@
+use chrono::{Duration, NaiveDate, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{config::Config, squarewordgen, sudokugen, telemetry, Game};
+
+/// How often to look for missing puzzles. They're made days ahead, so this only needs to be well
+/// under a day.
+const INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// The furthest time zones from UTC, which see a date start first and end last
+const LATEST_OFFSET_HOURS: i64 = 14;
+const EARLIEST_OFFSET_HOURS: i64 = 12;
+
+const TEST_SUDOKU: sudokugen::Sudoku = sudokugen::Sudoku {
+    puzzle: "4289751633761289459513642788197536242678415395342968-7-425873967836-945269543278-",
+    solution: "428975163376128945951364278819753624267841539534296817142587396783619452695432781",
+    difficulty: sudokugen::Difficulty::Medium,
+};
+
+/// Every date that's today somewhere in the world, followed by `days_ahead` more
+fn upcoming_days(days_ahead: i64) -> impl Iterator<Item = NaiveDate> {
+    let now = Utc::now();
+    let first = (now - Duration::hours(EARLIEST_OFFSET_HOURS)).date_naive();
+    let last =
+        (now + Duration::hours(LATEST_OFFSET_HOURS)).date_naive() + Duration::days(days_ahead);
+    first.iter_days().take_while(move |day| *day <= last)
+}
+
+async fn existing(pool: &PgPool, game: Game, day: NaiveDate) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar(&format!(
+        "select id from {} where day = $1",
+        game.puzzles_table()
+    ))
+    .bind(day)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Makes sure `game` has a puzzle on `day`, returning its id. If another server generated the
+/// same day in the meantime, its puzzle wins and ours is dropped.
+async fn ensure(
+    pool: &PgPool,
+    config: &Config,
+    game: Game,
+    day: NaiveDate,
+) -> Result<Uuid, String> {
+    if let Some(id) = existing(pool, game, day).await.map_err(|e| e.to_string())? {
+        return Ok(id);
+    }
+
+    let test_sudoku = config.test_sudoku;
+    // Generating only reshuffles a seed puzzle and takes microseconds, but it's still synchronous
+    // code, so it stays off the async workers anyway
+    let (puzzle, solution) = tokio::task::spawn_blocking(move || {
+        let start = std::time::Instant::now();
+        let generated = match game {
+            Game::Sudoku => {
+                if test_sudoku {
+                    (
+                        Some(TEST_SUDOKU.puzzle.to_string()),
+                        TEST_SUDOKU.solution.to_string(),
+                    )
+                } else {
+                    let generated = sudokugen::generate(sudokugen::Difficulty::Medium);
+                    (Some(generated.puzzle), generated.solution)
+                }
+            }
+            Game::Squareword => (None, squarewordgen::generate().to_string()),
+        };
+        telemetry::record_generation(game, start.elapsed());
+        generated
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let query = match puzzle {
+        Some(puzzle) => sqlx::query(
+            "insert into sudoku_puzzles (id, puzzle, solution, day) values ($1, $2, $3, $4) on conflict (day) do nothing",
+        )
+        .bind(Uuid::new_v4())
+        .bind(puzzle),
+        None => sqlx::query(
+            "insert into squareword_puzzles (id, solution, day) values ($1, $2, $3) on conflict (day) do nothing",
+        )
+        .bind(Uuid::new_v4()),
+    };
+    let inserted = query
+        .bind(solution)
+        .bind(day)
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?
+        .rows_affected();
+    if inserted > 0 {
+        tracing::info!("Generated {} puzzle for {}", game.name(), day);
+    }
+
+    existing(pool, game, day)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| {
+            format!(
+                "{} puzzle for {} vanished after inserting",
+                game.name(),
+                day
+            )
+        })
+}
+
+/// Generates any puzzles missing from today through `puzzle_days_ahead`. A day that fails is
+/// logged and tried again on the next pass.
+pub async fn fill(pool: &PgPool, config: &Config) {
+    for day in upcoming_days(config.puzzle_days_ahead) {
+        for game in [Game::Sudoku, Game::Squareword] {
+            if let Err(e) = ensure(pool, config, game, day).await {
+                tracing::error!(
+                    "Failed generating {} puzzle for {}: {}",
+                    game.name(),
+                    day,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Keeps filling in upcoming puzzles for as long as the server runs. Requests only ever read
+/// puzzles, so nothing races to create the same day.
+pub fn spawn(pool: PgPool, config: Config) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(INTERVAL).await;
+            fill(&pool, &config).await;
+        }
+    });
+}
@@ -0,0 +1,290 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sqlx::PgExecutor;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{config::Config, error::ApiError, AppState, User};
+
+const MIN_SECRET_LENGTH: usize = 32;
+const TOKEN_BYTES: usize = 32;
+
+/// A key for session tokens, given in configuration as `kid:secret`
+#[derive(Clone)]
+pub struct SigningKey {
+    kid: String,
+    secret: String,
+}
+
+impl FromStr for SigningKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kid, secret) = s
+            .split_once(':')
+            .ok_or_else(|| "expected a key in the form kid:secret".to_string())?;
+        if kid.is_empty() {
+            return Err("the key id must not be empty".to_string());
+        }
+        if secret.len() < MIN_SECRET_LENGTH {
+            return Err(format!(
+                "the secret for key {} must be at least {} characters",
+                kid, MIN_SECRET_LENGTH
+            ));
+        }
+
+        Ok(SigningKey {
+            kid: kid.to_string(),
+            secret: secret.to_string(),
+        })
+    }
+}
+
+impl SigningKey {
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+}
+
+// Keeps secrets out of logs of the configuration
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SigningKey({})", self.kid)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    #[serde(flatten)]
+    user: User,
+    iat: i64,
+    exp: i64,
+}
+
+/// Issues and checks session tokens. The first configured key signs new tokens, and every key can
+/// verify them, so a key can be rotated out once the tokens it signed have expired.
+pub struct Sessions {
+    kid: String,
+    encoding: EncodingKey,
+    decoding: HashMap<String, DecodingKey>,
+    access_ttl: Duration,
+    refresh_ttl: Duration,
+}
+
+impl Sessions {
+    pub fn new(config: &Config) -> Sessions {
+        let signing = config
+            .jwt_keys
+            .first()
+            .expect("the config requires at least one key");
+
+        Sessions {
+            kid: signing.kid.clone(),
+            encoding: EncodingKey::from_secret(signing.secret.as_bytes()),
+            decoding: config
+                .jwt_keys
+                .iter()
+                .map(|k| (k.kid.clone(), DecodingKey::from_secret(k.secret.as_bytes())))
+                .collect(),
+            access_ttl: Duration::minutes(config.access_token_minutes),
+            refresh_ttl: Duration::days(config.refresh_token_days),
+        }
+    }
+
+    fn access_token(
+        &self,
+        user: User,
+    ) -> Result<(String, DateTime<Utc>), jsonwebtoken::errors::Error> {
+        let now = Utc::now();
+        let expires_at = now + self.access_ttl;
+
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(self.kid.clone());
+        let token = jsonwebtoken::encode(
+            &header,
+            &Claims {
+                user,
+                iat: now.timestamp(),
+                exp: expires_at.timestamp(),
+            },
+            &self.encoding,
+        )?;
+
+        Ok((token, expires_at))
+    }
+
+    /// Checks an access token's signature and expiry, returning the user it was issued to
+    pub fn verify(&self, token: &str) -> Result<User, jsonwebtoken::errors::Error> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let key = header
+            .kid
+            .and_then(|kid| self.decoding.get(&kid))
+            .ok_or(ErrorKind::InvalidToken)?;
+
+        let claims =
+            jsonwebtoken::decode::<Claims>(token, key, &Validation::new(Algorithm::HS256))?;
+        Ok(claims.claims.user)
+    }
+
+    /// Like `verify`, with the reason a token was refused kept out of the response
+    pub fn authenticate(&self, token: &str) -> Result<User, ApiError> {
+        self.verify(token).map_err(|e| {
+            tracing::debug!("Rejected access token: {:?}", e);
+            ApiError::unauthorized("Your session has expired or isn't valid")
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Tokens {
+    access_token: String,
+    expires_at: DateTime<Utc>,
+    refresh_token: String,
+}
+
+/// Refresh and password reset tokens are random, and only their hash is stored
+pub fn hash_token(token: &str) -> String {
+    openssl::sha::sha256(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn random_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Starts a session for `user`, returning a fresh access token and refresh token
+pub async fn issue(state: &AppState, user: User) -> Result<Tokens, ApiError> {
+    let refresh_token = random_token();
+
+    sqlx::query(
+        "insert into refresh_tokens (id, user_id, token_hash, created_at, expires_at) values ($1, $2, $3, $4, $5)",
+    )
+    .bind(Uuid::new_v4())
+    .bind(user.id)
+    .bind(hash_token(&refresh_token))
+    .bind(Utc::now())
+    .bind(Utc::now() + state.sessions.refresh_ttl)
+    .execute(&state.pool)
+    .await
+    .map_err(|e| {
+        ApiError::database("saving refresh token", e)
+    })?;
+
+    let (access_token, expires_at) = state
+        .sessions
+        .access_token(user)
+        .map_err(|e| ApiError::internal("signing token", e))?;
+
+    Ok(Tokens {
+        access_token,
+        expires_at,
+        refresh_token,
+    })
+}
+
+/// Revokes a refresh token, returning who it was issued to if it was still usable
+pub async fn redeem<'c>(
+    executor: impl PgExecutor<'c>,
+    refresh_token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar(
+        "
+            update refresh_tokens set revoked_at = now()
+            where token_hash = $1 and revoked_at is null and expires_at > now()
+            returning user_id
+        ",
+    )
+    .bind(hash_token(refresh_token))
+    .fetch_optional(executor)
+    .await
+}
+
+/// Revokes every refresh token a user holds, signing them out everywhere once their access tokens
+/// expire
+pub async fn revoke_all<'c>(
+    executor: impl PgExecutor<'c>,
+    user_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "update refresh_tokens set revoked_at = now() where user_id = $1 and revoked_at is null",
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// Swaps a refresh token for a new access token and refresh token. Each refresh token can only be
+/// used once.
+#[utoipa::path(
+    post,
+    path = "/refresh",
+    tag = "auth",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "A new session", body = Tokens),
+        (status = 401, description = "The refresh token can't be used", body = ErrorBody),
+    )
+)]
+pub async fn refresh(
+    State(state): State<AppState>,
+    Json(request): Json<RefreshRequest>,
+) -> Result<Json<Tokens>, ApiError> {
+    let map_err = |e: sqlx::Error| ApiError::database("refreshing session", e);
+
+    let user_id = redeem(&state.pool, &request.refresh_token)
+        .await
+        .map_err(map_err)?;
+    let user_id = user_id
+        .ok_or_else(|| ApiError::unauthorized("Refresh token is invalid, expired or revoked"))?;
+
+    let user: User = sqlx::query_as("select * from users where id = $1")
+        .bind(user_id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(map_err)?;
+
+    issue(&state, user).await.map(Json)
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct LogoutRequest {
+    refresh_token: String,
+}
+
+/// Revokes a refresh token. Access tokens already handed out stay valid until they expire.
+#[utoipa::path(
+    post,
+    path = "/logout",
+    tag = "auth",
+    request_body = LogoutRequest,
+    responses((status = 204, description = "Signed out"))
+)]
+pub async fn logout(
+    State(state): State<AppState>,
+    Json(request): Json<LogoutRequest>,
+) -> Result<StatusCode, ApiError> {
+    sqlx::query(
+        "update refresh_tokens set revoked_at = now() where token_hash = $1 and revoked_at is null",
+    )
+    .bind(hash_token(&request.refresh_token))
+    .execute(&state.pool)
+    .await
+    .map_err(|e| ApiError::database("revoking refresh token", e))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
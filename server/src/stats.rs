@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+
+use axum::{extract::State, Json};
+use chrono::{Duration, NaiveDate};
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{error::ApiError, AppState, Game, User};
+
+#[derive(Serialize, ToSchema)]
+pub struct GameStats {
+    played: i64,
+    wins: i64,
+    win_percentage: f64,
+    current_streak: i64,
+    longest_streak: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SudokuStats {
+    #[serde(flatten)]
+    game: GameStats,
+    average_seconds: Option<f64>,
+    best_seconds: Option<i32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SquarewordStats {
+    #[serde(flatten)]
+    game: GameStats,
+    guess_distribution: BTreeMap<i32, i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StatsResponse {
+    sudoku: SudokuStats,
+    squareword: SquarewordStats,
+}
+
+#[derive(sqlx::FromRow)]
+struct ScoreRow {
+    day: NaiveDate,
+    winner: bool,
+    late: bool,
+    elapsed_seconds: Option<i32>,
+    guess_count: Option<i32>,
+}
+
+async fn fetch_scores(
+    state: &AppState,
+    user_id: Uuid,
+    game: Game,
+    today: NaiveDate,
+) -> Result<Vec<ScoreRow>, ApiError> {
+    sqlx::query_as(&format!(
+        "
+            select p.day, s.winner, s.late, s.elapsed_seconds, s.guess_count
+            from {} s
+            join {} p on p.id = s.puzzle_id
+            where s.user_id = $1 and p.day <= $2
+            order by p.day
+        ",
+        game.scores_table(),
+        game.puzzles_table(),
+    ))
+    .bind(user_id)
+    .bind(today)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|e| ApiError::database("querying stats", e))
+}
+
+/// Returns the current and longest runs of consecutive days in `days`, which must be sorted. A
+/// streak is still current if its last day is yesterday, since today's puzzle may not be done yet.
+fn streaks(days: &[NaiveDate], today: NaiveDate) -> (i64, i64) {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut previous: Option<NaiveDate> = None;
+
+    for &day in days {
+        run = match previous {
+            Some(p) if day - p == Duration::days(1) => run + 1,
+            Some(p) if day == p => run,
+            _ => 1,
+        };
+        longest = longest.max(run);
+        previous = Some(day);
+    }
+
+    let current = match previous {
+        Some(last) if today - last <= Duration::days(1) => run,
+        _ => 0,
+    };
+
+    (current, longest)
+}
+
+fn game_stats(rows: &[ScoreRow], today: NaiveDate) -> GameStats {
+    let played = rows.len() as i64;
+    let wins = rows.iter().filter(|r| r.winner).count() as i64;
+
+    // Archive solves count as wins, but only on-the-day wins keep a streak alive
+    let streak_days: Vec<NaiveDate> = rows
+        .iter()
+        .filter(|r| r.winner && !r.late)
+        .map(|r| r.day)
+        .collect();
+    let (current_streak, longest_streak) = streaks(&streak_days, today);
+
+    GameStats {
+        played,
+        wins,
+        win_percentage: if played == 0 {
+            0.0
+        } else {
+            wins as f64 * 100.0 / played as f64
+        },
+        current_streak,
+        longest_streak,
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/me/stats",
+    tag = "account",
+    responses(
+        (status = 200, description = "The signed in user's stats", body = StatsResponse),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+pub async fn stats(
+    user: User,
+    State(state): State<AppState>,
+) -> Result<Json<StatsResponse>, ApiError> {
+    for_user(&state, user.id, user.today(&state).await?)
+        .await
+        .map(Json)
+}
+
+/// Stats for any user, with streaks counted up to `today` in their time zone. Callers check that
+/// `user_id` is the signed in user or has a public profile.
+pub async fn for_user(
+    state: &AppState,
+    user_id: Uuid,
+    today: NaiveDate,
+) -> Result<StatsResponse, ApiError> {
+    let sudoku_rows = fetch_scores(state, user_id, Game::Sudoku, today).await?;
+    let sudoku_times: Vec<i32> = sudoku_rows
+        .iter()
+        .filter(|r| r.winner)
+        .filter_map(|r| r.elapsed_seconds)
+        .collect();
+
+    let squareword_rows = fetch_scores(state, user_id, Game::Squareword, today).await?;
+    let mut guess_distribution = BTreeMap::new();
+    for guesses in squareword_rows
+        .iter()
+        .filter(|r| r.winner)
+        .filter_map(|r| r.guess_count)
+    {
+        *guess_distribution.entry(guesses).or_insert(0) += 1;
+    }
+
+    Ok(StatsResponse {
+        sudoku: SudokuStats {
+            game: game_stats(&sudoku_rows, today),
+            average_seconds: if sudoku_times.is_empty() {
+                None
+            } else {
+                Some(
+                    sudoku_times.iter().map(|&t| t as f64).sum::<f64>() / sudoku_times.len() as f64,
+                )
+            },
+            best_seconds: sudoku_times.iter().copied().min(),
+        },
+        squareword: SquarewordStats {
+            game: game_stats(&squareword_rows, today),
+            guess_distribution,
+        },
+    })
+}
@@ -10,6 +10,13 @@ pub struct Sudoku {
     pub difficulty: Difficulty,
 }
 
+/// A puzzle made from one of the seeds, owning its grids
+#[derive(Clone, Debug)]
+pub struct Generated {
+    pub puzzle: String,
+    pub solution: String,
+}
+
 const SEEDS: [Sudoku; 40] = [
     Sudoku {
         puzzle: "g--d--caf---g----ii-f--hg-bb-iaedhgc--afcg--d-g-b-----f-d--abc---b------c--h-bfia",
@@ -482,18 +489,14 @@ pub fn get_sequence(layout: &Layout, seed_sequence: &'static str, token_map: &To
     ))
 }
 
-pub fn generate(difficulty: Difficulty) -> Sudoku {
+pub fn generate(difficulty: Difficulty) -> Generated {
     let mut rng = rand::thread_rng();
     let seed = get_seed(difficulty, &mut rng);
     let layout = &get_layout(&get_base_layout(), &mut rng);
     let token_map = &get_token_map(&mut rng);
-    let puzzle = Box::leak(Box::new(get_sequence(layout, seed.puzzle, token_map)));
-    let solution = Box::leak(Box::new(get_sequence(layout, seed.solution, token_map)));
-    Sudoku {
-        puzzle,
-        solution,
-        difficulty,
-    }
+    let puzzle = get_sequence(layout, seed.puzzle, token_map);
+    let solution = get_sequence(layout, seed.solution, token_map);
+    Generated { puzzle, solution }
 }
 
 // #[cfg(test)]
@@ -0,0 +1,156 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::{header::CONTENT_TYPE, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::{error::ApiError, AppState, Game};
+
+/// Request latencies are mostly database round trips, from a few milliseconds up to an identity
+/// provider timing out
+const REQUEST_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Generating reshuffles a seed puzzle, which takes a few microseconds. The upper buckets are
+/// there to catch a regression.
+const GENERATION_BUCKETS: &[f64] = &[1e-6, 2.5e-6, 5e-6, 1e-5, 2.5e-5, 5e-5, 1e-4, 1e-3, 1e-2];
+
+/// How often the daily players and solves are recounted. They move slowly, and counting scans a
+/// day of moves.
+const DAILY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Starts collecting metrics for the process, returning the handle that renders them
+pub fn install() -> PrometheusHandle {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("gotd_http_request_duration_seconds".to_string()),
+            REQUEST_BUCKETS,
+        )
+        .and_then(|builder| {
+            builder.set_buckets_for_metric(
+                Matcher::Full("gotd_puzzle_generation_seconds".to_string()),
+                GENERATION_BUCKETS,
+            )
+        })
+        .expect("the histogram buckets aren't empty")
+        .install_recorder()
+        .expect("metrics are only installed once")
+}
+
+/// Counts and times every request by route and status. Routes are labelled by their pattern, so
+/// ids in the path don't make a series each.
+pub async fn track<B>(request: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |p| p.as_str().to_string());
+
+    let response = next.run(request).await;
+
+    let labels = [
+        ("method", method),
+        ("path", path),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    counter!("gotd_http_requests_total", 1, &labels);
+    histogram!(
+        "gotd_http_request_duration_seconds",
+        start.elapsed().as_secs_f64(),
+        &labels
+    );
+
+    response
+}
+
+/// Records a sign in attempt, by `method` like `oidc` or `password`
+pub fn record_login<T>(method: &'static str, result: &Result<T, ApiError>) {
+    let outcome = if result.is_ok() { "success" } else { "failure" };
+    counter!("gotd_logins_total", 1, "method" => method, "outcome" => outcome);
+}
+
+pub fn record_solve(game: Game, late: bool) {
+    counter!(
+        "gotd_solves_total",
+        1,
+        "game" => game.name(),
+        "late" => late.to_string()
+    );
+}
+
+/// Records how long generating a puzzle took, not counting the wait for a blocking thread
+pub fn record_generation(game: Game, took: Duration) {
+    histogram!(
+        "gotd_puzzle_generation_seconds",
+        took.as_secs_f64(),
+        "game" => game.name()
+    );
+}
+
+/// Players and solves over the last day, from the database so they're right across restarts and
+/// every server agrees
+async fn record_daily(state: &AppState) -> Result<(), sqlx::Error> {
+    let players: Vec<(String, i64)> = sqlx::query_as(
+        "select game, count(distinct user_id) from moves where created_at > now() - interval '1 day' group by game",
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    for game in [Game::Sudoku, Game::Squareword] {
+        let active = players
+            .iter()
+            .find(|(name, _)| name == game.name())
+            .map_or(0, |(_, count)| *count);
+        gauge!("gotd_daily_active_players", active as f64, "game" => game.name());
+
+        let solves: i64 = sqlx::query_scalar(&format!(
+            "select count(*) from {} where completed_at > now() - interval '1 day'",
+            game.scores_table()
+        ))
+        .fetch_one(&state.pool)
+        .await?;
+        gauge!("gotd_daily_solves", solves as f64, "game" => game.name());
+    }
+
+    Ok(())
+}
+
+/// Keeps the daily gauges current for as long as the server runs, so scrapes never wait on the
+/// database
+pub fn spawn_daily(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            // Scrapes keep showing the last counts while the database isn't answering
+            if let Err(e) = record_daily(&state).await {
+                tracing::error!("Failed counting daily players: {}", e);
+            }
+            tokio::time::sleep(DAILY_INTERVAL).await;
+        }
+    });
+}
+
+/// Every metric in the Prometheus text format
+pub async fn render(State(state): State<AppState>) -> Response {
+    gauge!("gotd_db_pool_connections", state.pool.size() as f64);
+    gauge!(
+        "gotd_db_pool_idle_connections",
+        state.pool.num_idle() as f64
+    );
+    gauge!(
+        "gotd_db_pool_max_connections",
+        state.config.db_pool_size as f64
+    );
+
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+        .into_response()
+}
@@ -0,0 +1,76 @@
+use axum::{
+    extract::State,
+    http::{HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+
+use crate::config::Config;
+
+/// Where the current version of the API is mounted
+pub const V1: &str = "/api/v1";
+
+/// When the unversioned paths were superseded by `/api/v1`
+const LEGACY_DEPRECATED_ON: (i32, u32, u32) = (2023, 10, 20);
+
+fn midnight(day: NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0).expect("midnight is a valid time"))
+}
+
+/// Marks every response of a retired set of routes as deprecated (RFC 9745), with a link to the
+/// same path in the version that replaces it and, once it's been decided, the date it goes away
+/// (RFC 8594). Clients can warn about it before anything breaks.
+#[derive(Clone)]
+pub struct Deprecation {
+    since: DateTime<Utc>,
+    sunset: Option<DateTime<Utc>>,
+    successor: &'static str,
+}
+
+impl Deprecation {
+    /// For the paths from before the API was versioned, which `/api/v1` replaced
+    pub fn legacy(config: &Config) -> Deprecation {
+        let (year, month, day) = LEGACY_DEPRECATED_ON;
+        Deprecation {
+            since: midnight(
+                NaiveDate::from_ymd_opt(year, month, day).expect("the deprecation date is valid"),
+            ),
+            sunset: config.legacy_api_sunset.map(midnight),
+            successor: V1,
+        }
+    }
+}
+
+pub async fn deprecated<B>(
+    State(deprecation): State<Deprecation>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let successor = format!(
+        "<{}{}>; rel=\"successor-version\"",
+        deprecation.successor,
+        request.uri().path()
+    );
+
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+
+    headers.insert(
+        HeaderName::from_static("deprecation"),
+        HeaderValue::from_str(&format!("@{}", deprecation.since.timestamp()))
+            .expect("a timestamp is a valid header"),
+    );
+    if let Some(sunset) = deprecation.sunset {
+        headers.insert(
+            HeaderName::from_static("sunset"),
+            HeaderValue::from_str(&sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+                .expect("a date is a valid header"),
+        );
+    }
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.append(axum::http::header::LINK, link);
+    }
+
+    response
+}